@@ -0,0 +1,286 @@
+use crate::client::{Channel, Client, Event};
+use crate::errors::*;
+use std::{
+    fmt, thread,
+    time::{Duration, Instant},
+};
+
+// Parameters shared by the benchmarks. Warmup messages are sent before the
+// measured ones, and are not accounted for.
+#[derive(Clone, Debug)]
+pub struct BenchOptions {
+    pub subject: String,
+    pub messages: usize,
+    pub message_size: usize,
+    pub warmup: usize,
+    // How long to wait for the next message before giving up
+    pub timeout: Duration,
+}
+
+impl Default for BenchOptions {
+    fn default() -> BenchOptions {
+        BenchOptions {
+            subject: "bench".to_owned(),
+            messages: 100_000,
+            message_size: 128,
+            warmup: 1_000,
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Throughput {
+    pub messages: usize,
+    pub bytes: usize,
+    pub elapsed: Duration,
+}
+
+impl Throughput {
+    fn new(messages: usize, message_size: usize, elapsed: Duration) -> Throughput {
+        Throughput {
+            messages,
+            bytes: messages * message_size,
+            elapsed,
+        }
+    }
+
+    pub fn messages_per_sec(&self) -> f64 {
+        rate(self.messages, self.elapsed)
+    }
+
+    pub fn bytes_per_sec(&self) -> f64 {
+        rate(self.bytes, self.elapsed)
+    }
+}
+
+impl fmt::Display for Throughput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} msgs in {:?}: {:.0} msgs/s, {:.2} MB/s",
+            self.messages,
+            self.elapsed,
+            self.messages_per_sec(),
+            self.bytes_per_sec() / 1_000_000.0
+        )
+    }
+}
+
+fn rate(count: usize, elapsed: Duration) -> f64 {
+    if elapsed.is_zero() {
+        return 0.0;
+    }
+    count as f64 / elapsed.as_secs_f64()
+}
+
+// Round-trip times of the measured requests, sorted
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Latency {
+    samples: Vec<Duration>,
+}
+
+impl Latency {
+    fn new(mut samples: Vec<Duration>) -> Latency {
+        samples.sort_unstable();
+        Latency { samples }
+    }
+
+    pub fn samples(&self) -> &[Duration] {
+        &self.samples
+    }
+
+    // Nearest-rank percentile, `p` being between 0 and 100
+    pub fn percentile(&self, p: f64) -> Duration {
+        if self.samples.is_empty() {
+            return Duration::ZERO;
+        }
+        let rank = (p.clamp(0.0, 100.0) / 100.0 * self.samples.len() as f64).ceil() as usize;
+        self.samples[rank.saturating_sub(1)]
+    }
+
+    pub fn min(&self) -> Duration {
+        self.percentile(0.0)
+    }
+
+    pub fn max(&self) -> Duration {
+        self.percentile(100.0)
+    }
+
+    pub fn mean(&self) -> Duration {
+        if self.samples.is_empty() {
+            return Duration::ZERO;
+        }
+        self.samples.iter().sum::<Duration>() / self.samples.len() as u32
+    }
+}
+
+impl fmt::Display for Latency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} requests: min {:?}, p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+            self.samples.len(),
+            self.min(),
+            self.percentile(50.0),
+            self.percentile(90.0),
+            self.percentile(99.0),
+            self.max()
+        )
+    }
+}
+
+// Measures how fast `client` can publish, including the final flush
+pub fn publish(client: &mut Client, options: &BenchOptions) -> Result<Throughput, NatsError> {
+    let payload = vec![b'x'; options.message_size];
+    for _ in 0..options.warmup {
+        client.publish(&options.subject, &payload)?;
+    }
+    client.flush()?;
+    let started = Instant::now();
+    for _ in 0..options.messages {
+        client.publish(&options.subject, &payload)?;
+    }
+    client.flush()?;
+    Ok(Throughput::new(
+        options.messages,
+        options.message_size,
+        started.elapsed(),
+    ))
+}
+
+// Publishes through `publisher` while `subscriber` receives the messages from
+// another thread. Returns the publishing and the receiving throughputs.
+pub fn pubsub(
+    publisher: &mut Client,
+    subscriber: &mut Client,
+    options: &BenchOptions,
+) -> Result<(Throughput, Throughput), NatsError> {
+    let channel = subscriber.subscribe(&options.subject, None)?;
+    // The server processed the subscription once it answered the PING
+    subscriber.rtt()?;
+    let (published, received) = thread::scope(|scope| {
+        let receiver = scope.spawn(|| receive(subscriber, channel, options));
+        let published = publish(publisher, options);
+        (published, receiver.join().unwrap())
+    });
+    subscriber.unsubscribe(channel)?;
+    Ok((published?, received?))
+}
+
+fn receive(
+    subscriber: &mut Client,
+    channel: Channel,
+    options: &BenchOptions,
+) -> Result<Throughput, NatsError> {
+    for _ in 0..options.warmup {
+        next_message(subscriber, channel, options.timeout)?;
+    }
+    let mut started = None;
+    for _ in 0..options.messages {
+        next_message(subscriber, channel, options.timeout)?;
+        started.get_or_insert_with(Instant::now);
+    }
+    let elapsed = started.map(|started| started.elapsed()).unwrap_or_default();
+    // The clock starts once the first message arrived, so that the time
+    // spent waiting for the publisher isn't measured: that message is not
+    // counted either
+    Ok(Throughput::new(
+        options.messages.saturating_sub(1),
+        options.message_size,
+        elapsed,
+    ))
+}
+
+fn next_message(
+    client: &mut Client,
+    channel: Channel,
+    timeout: Duration,
+) -> Result<Event, NatsError> {
+    client.select(&[channel], timeout)?.ok_or_else(|| {
+        NatsError::from((
+            ErrorKind::Timeout,
+            "The benchmark stopped receiving messages",
+        ))
+    })
+}
+
+// Measures request round-trip times, `responder` echoing the requests from
+// another thread
+pub fn request_latency(
+    requester: &mut Client,
+    responder: &mut Client,
+    options: &BenchOptions,
+) -> Result<Latency, NatsError> {
+    let channel = responder.subscribe(&options.subject, None)?;
+    responder.rtt()?;
+    let (latency, responded) = thread::scope(|scope| {
+        let echo = scope.spawn(|| -> Result<(), NatsError> {
+            for _ in 0..options.warmup + options.messages {
+                let event = next_message(responder, channel, options.timeout)?;
+                event.respond(responder, &event.msg)?;
+                responder.flush()?;
+            }
+            Ok(())
+        });
+        let latency = requests(requester, options);
+        (latency, echo.join().unwrap())
+    });
+    responder.unsubscribe(channel)?;
+    let latency = latency?;
+    responded?;
+    Ok(latency)
+}
+
+fn requests(requester: &mut Client, options: &BenchOptions) -> Result<Latency, NatsError> {
+    let payload = vec![b'x'; options.message_size];
+    for _ in 0..options.warmup {
+        requester.request(&options.subject, &payload, options.timeout)?;
+    }
+    let mut samples = Vec::with_capacity(options.messages);
+    for _ in 0..options.messages {
+        let started = Instant::now();
+        requester.request(&options.subject, &payload, options.timeout)?;
+        samples.push(started.elapsed());
+    }
+    Ok(Latency::new(samples))
+}
+
+// Runs `bench` once per message size
+pub fn sweep<F, T>(
+    sizes: &[usize],
+    options: &BenchOptions,
+    mut bench: F,
+) -> Result<Vec<(usize, T)>, NatsError>
+where
+    F: FnMut(&BenchOptions) -> Result<T, NatsError>,
+{
+    let mut results = Vec::with_capacity(sizes.len());
+    for &message_size in sizes {
+        let options = BenchOptions {
+            message_size,
+            ..options.clone()
+        };
+        results.push((message_size, bench(&options)?));
+    }
+    Ok(results)
+}
+
+#[test]
+fn bench_stats_test() {
+    let latency = Latency::new((1..=100).rev().map(Duration::from_millis).collect());
+    assert_eq!(latency.min(), Duration::from_millis(1));
+    assert_eq!(latency.percentile(50.0), Duration::from_millis(50));
+    assert_eq!(latency.percentile(99.0), Duration::from_millis(99));
+    assert_eq!(latency.max(), Duration::from_millis(100));
+    assert_eq!(latency.mean(), Duration::from_micros(50_500));
+    assert_eq!(Latency::default().percentile(50.0), Duration::ZERO);
+
+    let throughput = Throughput::new(1000, 100, Duration::from_millis(500));
+    assert_eq!(throughput.messages_per_sec(), 2000.0);
+    assert_eq!(throughput.bytes_per_sec(), 200_000.0);
+    assert_eq!(
+        Throughput::new(0, 100, Duration::ZERO).messages_per_sec(),
+        0.0
+    );
+}
@@ -0,0 +1,238 @@
+use crate::errors::*;
+use crate::reply::Reply;
+use bytes::Bytes;
+use openssl::sha::sha256;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+// Identifies a request, so that retries can be recognized by responders
+pub const REQUEST_ID_HEADER: &str = "Nats-Request-Id";
+
+type CacheKey = (String, [u8; 32]);
+
+#[derive(Debug, Default)]
+struct CacheState {
+    entries: HashMap<CacheKey, (Instant, Reply)>,
+    in_flight: HashSet<CacheKey>,
+}
+
+// Replies to idempotent requests, keyed by subject and payload hash.
+// Clones share the same entries, so that a cache can be used by several clients.
+#[derive(Debug, Clone)]
+pub struct RequestCache {
+    inner: Arc<(Mutex<CacheState>, Condvar)>,
+    ttl: Duration,
+}
+
+// Runs a function when dropped, including while unwinding from a panic
+struct OnDrop<F: FnMut()>(F);
+
+impl<F: FnMut()> Drop for OnDrop<F> {
+    fn drop(&mut self) {
+        (self.0)()
+    }
+}
+
+impl RequestCache {
+    pub fn new(ttl: Duration) -> RequestCache {
+        RequestCache {
+            inner: Arc::new((Mutex::new(CacheState::default()), Condvar::new())),
+            ttl,
+        }
+    }
+
+    pub fn invalidate(&self, subject: &str, msg: &[u8]) {
+        let key = (subject.to_owned(), sha256(msg));
+        self.inner.0.lock().unwrap().entries.remove(&key);
+    }
+
+    pub fn clear(&self) {
+        self.inner.0.lock().unwrap().entries.clear();
+    }
+
+    pub(crate) fn get_or_fetch<F>(
+        &self,
+        subject: &str,
+        msg: &[u8],
+        fetch: F,
+    ) -> Result<Reply, NatsError>
+    where
+        F: FnOnce() -> Result<Reply, NatsError>,
+    {
+        let key = (subject.to_owned(), sha256(msg));
+        let (ref lock, ref cvar) = *self.inner;
+        {
+            let mut state = lock.lock().unwrap();
+            loop {
+                if let Some((expires, event)) = state.entries.get(&key) {
+                    if Instant::now() < *expires {
+                        return Ok(event.clone());
+                    }
+                }
+                if !state.in_flight.contains(&key) {
+                    break;
+                }
+                state = cvar.wait(state).unwrap();
+            }
+            state.in_flight.insert(key.clone());
+        }
+        // Requests waiting for this one must not wait forever if it panics
+        let _in_flight = OnDrop(|| {
+            lock.lock().unwrap().in_flight.remove(&key);
+            cvar.notify_all();
+        });
+        let res = fetch();
+        if let Ok(ref event) = res {
+            let mut state = lock.lock().unwrap();
+            let now = Instant::now();
+            state.entries.retain(|_, (expires, _)| now < *expires);
+            state
+                .entries
+                .insert(key.clone(), (now + self.ttl, event.clone()));
+        }
+        res
+    }
+}
+
+#[derive(Debug, Default)]
+struct ReplyCacheState {
+    entries: HashMap<(String, String), (Instant, Bytes)>,
+    in_flight: HashSet<(String, String)>,
+}
+
+// Replies sent by a responder, keyed by subject and request id, so that a
+// retried request gets the same reply without being processed again.
+// Clones share the same entries.
+#[derive(Debug, Clone)]
+pub struct ReplyCache {
+    inner: Arc<(Mutex<ReplyCacheState>, Condvar)>,
+    ttl: Duration,
+}
+
+impl ReplyCache {
+    pub fn new(ttl: Duration) -> ReplyCache {
+        ReplyCache {
+            inner: Arc::new((Mutex::new(ReplyCacheState::default()), Condvar::new())),
+            ttl,
+        }
+    }
+
+    pub fn clear(&self) {
+        self.inner.0.lock().unwrap().entries.clear();
+    }
+
+    // Returns the cached reply, or computes it. A duplicate request received
+    // while the original one is processed waits for its reply.
+    pub(crate) fn get_or_compute<F>(
+        &self,
+        subject: &str,
+        request_id: &str,
+        compute: F,
+    ) -> Result<Bytes, NatsError>
+    where
+        F: FnOnce() -> Result<Bytes, NatsError>,
+    {
+        let key = (subject.to_owned(), request_id.to_owned());
+        let (ref lock, ref cvar) = *self.inner;
+        {
+            let mut state = lock.lock().unwrap();
+            loop {
+                if let Some((expires, reply)) = state.entries.get(&key) {
+                    if Instant::now() < *expires {
+                        return Ok(reply.clone());
+                    }
+                }
+                if !state.in_flight.contains(&key) {
+                    break;
+                }
+                state = cvar.wait(state).unwrap();
+            }
+            state.in_flight.insert(key.clone());
+        }
+        let _in_flight = OnDrop(|| {
+            lock.lock().unwrap().in_flight.remove(&key);
+            cvar.notify_all();
+        });
+        let res = compute();
+        if let Ok(ref reply) = res {
+            let mut state = lock.lock().unwrap();
+            let now = Instant::now();
+            state.entries.retain(|_, (expires, _)| now < *expires);
+            state
+                .entries
+                .insert(key.clone(), (now + self.ttl, reply.clone()));
+        }
+        res
+    }
+}
+
+#[test]
+fn reply_cache_test() {
+    let cache = ReplyCache::new(Duration::from_secs(60));
+    let mut calls = 0;
+    for _ in 0..2 {
+        let reply = cache
+            .get_or_compute("svc", "id1", || {
+                calls += 1;
+                Ok(Bytes::from_static(b"done"))
+            })
+            .unwrap();
+        assert_eq!(reply, Bytes::from_static(b"done"));
+    }
+    assert_eq!(calls, 1);
+    cache
+        .get_or_compute("other", "id1", || {
+            calls += 1;
+            Ok(Bytes::new())
+        })
+        .unwrap();
+    assert_eq!(calls, 2);
+
+    // Failures are not cached
+    assert!(cache
+        .get_or_compute("svc", "id2", || Err(NatsError::from((
+            ErrorKind::TypeError,
+            "failed"
+        ))))
+        .is_err());
+    cache
+        .get_or_compute("svc", "id2", || {
+            calls += 1;
+            Ok(Bytes::new())
+        })
+        .unwrap();
+    assert_eq!(calls, 3);
+
+    // A duplicate doesn't wait for a request whose processing panicked
+    let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        cache.get_or_compute("svc", "id3", || panic!("handler failed"))
+    }));
+    assert!(res.is_err());
+    cache
+        .get_or_compute("svc", "id3", || {
+            calls += 1;
+            Ok(Bytes::new())
+        })
+        .unwrap();
+    assert_eq!(calls, 4);
+}
+
+#[test]
+fn request_cache_panic_test() {
+    use std::panic::{self, AssertUnwindSafe};
+
+    let cache = RequestCache::new(Duration::from_secs(60));
+    let res = panic::catch_unwind(AssertUnwindSafe(|| {
+        cache.get_or_fetch("svc", b"req", || panic!("fetch failed"))
+    }));
+    assert!(res.is_err());
+    // Doesn't wait for the request that panicked
+    let res = cache.get_or_fetch("svc", b"req", || {
+        Err(NatsError::from((ErrorKind::TypeError, "failed")))
+    });
+    assert_eq!(res.unwrap_err().kind(), ErrorKind::TypeError);
+    assert!(cache.inner.0.lock().unwrap().in_flight.is_empty());
+}
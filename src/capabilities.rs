@@ -0,0 +1,155 @@
+use crate::errors::*;
+use serde_json::{Map, Value};
+
+// Features of the server a client is connected to, deduced from its INFO.
+// Servers from the 1.x series support neither headers nor no-responders
+// notifications, and servers speaking the original protocol (0) don't
+// advertise the other members of their cluster, so these features are
+// disabled rather than relying on what such servers would do with them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServerCapabilities {
+    pub version: Option<String>,
+    pub proto: u64,
+    pub headers: bool,
+    pub no_responders: bool,
+    pub connect_urls: bool,
+}
+
+// INFO sent by a server when the client connected, updated with the changes
+// the server announced since then
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ServerInfo {
+    pub server_id: String,
+    pub server_name: Option<String>,
+    pub version: Option<String>,
+    pub proto: u64,
+    pub max_payload: usize,
+    pub tls_required: bool,
+    pub auth_required: bool,
+    // Whether the server supports headers, as announced
+    pub headers: bool,
+    pub client_id: Option<u64>,
+    pub client_ip: Option<String>,
+    pub cluster: Option<String>,
+    pub connect_urls: Vec<String>,
+}
+
+impl ServerInfo {
+    pub(crate) fn from_info(info: &Map<String, Value>) -> ServerInfo {
+        let string = |key: &str| info.get(key).and_then(|v| v.as_str()).map(|v| v.to_owned());
+        let flag = |key: &str| info.get(key).and_then(|v| v.as_bool()).unwrap_or(false);
+        ServerInfo {
+            server_id: string("server_id").unwrap_or_default(),
+            server_name: string("server_name"),
+            version: string("version"),
+            proto: info.get("proto").and_then(|v| v.as_u64()).unwrap_or(0),
+            max_payload: info
+                .get("max_payload")
+                .and_then(|v| v.as_u64())
+                .unwrap_or(0) as usize,
+            tls_required: flag("tls_required"),
+            auth_required: flag("auth_required"),
+            headers: flag("headers"),
+            client_id: info.get("client_id").and_then(|v| v.as_u64()),
+            client_ip: string("client_ip"),
+            cluster: string("cluster"),
+            connect_urls: info
+                .get("connect_urls")
+                .and_then(|v| v.as_array())
+                .map(|urls| {
+                    urls.iter()
+                        .filter_map(|url| url.as_str().map(|url| url.to_owned()))
+                        .collect()
+                })
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl ServerCapabilities {
+    pub(crate) fn from_info(info: &Map<String, Value>) -> ServerCapabilities {
+        let version = info
+            .get("version")
+            .and_then(|v| v.as_str())
+            .map(|v| v.to_owned());
+        let proto = info.get("proto").and_then(|v| v.as_u64()).unwrap_or(0);
+        let major = version
+            .as_deref()
+            .and_then(|version| version.split('.').next())
+            .and_then(|major| major.parse::<u64>().ok());
+        let headers = major.is_none_or(|major| major >= 2)
+            && info
+                .get("headers")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(false);
+        ServerCapabilities {
+            version,
+            proto,
+            headers,
+            no_responders: headers,
+            connect_urls: proto >= 1,
+        }
+    }
+
+    // Returns an `Unsupported` error naming the server version if
+    // `supported` is false
+    pub(crate) fn check(&self, supported: bool, errmsg: &'static str) -> Result<(), NatsError> {
+        if supported {
+            return Ok(());
+        }
+        let version = self.version.as_deref().unwrap_or("unknown");
+        Err(NatsError::from((
+            ErrorKind::Unsupported,
+            errmsg,
+            format!("server version {}, protocol {}", version, self.proto),
+        )))
+    }
+}
+
+#[test]
+fn server_capabilities_test() {
+    use serde_json::json;
+
+    let info = |value: Value| value.as_object().unwrap().clone();
+
+    let caps = ServerCapabilities::from_info(&info(json!({
+        "version": "1.4.1",
+        "proto": 1,
+        "headers": true,
+    })));
+    assert!(!caps.headers && !caps.no_responders && caps.connect_urls);
+    let err = caps
+        .check(caps.headers, "The server doesn't support headers")
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Unsupported);
+    assert_eq!(
+        err.to_string(),
+        "The server doesn't support headers: server version 1.4.1, protocol 1"
+    );
+
+    let caps = ServerCapabilities::from_info(&info(json!({
+        "version": "2.10.4",
+        "proto": 1,
+        "headers": true,
+    })));
+    assert!(caps.headers && caps.no_responders && caps.connect_urls);
+
+    let caps = ServerCapabilities::from_info(&info(json!({ "version": "0.9.6" })));
+    assert!(!caps.headers && !caps.connect_urls);
+
+    let server_info = ServerInfo::from_info(&info(json!({
+        "server_id": "NCXBF5",
+        "version": "2.10.4",
+        "proto": 1,
+        "max_payload": 1048576,
+        "client_id": 7,
+        "client_ip": "10.0.0.4",
+        "cluster": "east",
+        "connect_urls": ["10.0.0.1:4222", "10.0.0.2:4222"],
+    })));
+    assert_eq!(server_info.server_id, "NCXBF5");
+    assert_eq!(server_info.client_id, Some(7));
+    assert_eq!(server_info.cluster.as_deref(), Some("east"));
+    assert_eq!(server_info.connect_urls.len(), 2);
+    assert!(!server_info.headers);
+}
@@ -0,0 +1,201 @@
+use crate::client::Event;
+use crate::encryption::PAYLOAD_ENCRYPTION_HEADER;
+use crate::errors::*;
+use bytes::Bytes;
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+use std::io::{Read, Write};
+
+pub const PAYLOAD_ENCODING_HEADER: &str = "Nats-Payload-Encoding";
+
+const DEFAULT_COMPRESSION_THRESHOLD: usize = 1024;
+// Decompressed payloads larger than this are rejected, so that a small
+// message can't exhaust the memory of the receiver
+const MAX_DECOMPRESSED_SIZE: usize = 64 * 1024 * 1024;
+
+// Compression algorithms, available with the `gzip` and `zstd` features
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CompressionAlgorithm {
+    Gzip,
+    Zstd,
+}
+
+impl CompressionAlgorithm {
+    pub fn name(self) -> &'static str {
+        match self {
+            CompressionAlgorithm::Gzip => "gzip",
+            CompressionAlgorithm::Zstd => "zstd",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<CompressionAlgorithm> {
+        match name {
+            "gzip" => Some(CompressionAlgorithm::Gzip),
+            "zstd" => Some(CompressionAlgorithm::Zstd),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn check_supported(self) -> Result<(), NatsError> {
+        let supported = match self {
+            CompressionAlgorithm::Gzip => cfg!(feature = "gzip"),
+            CompressionAlgorithm::Zstd => cfg!(feature = "zstd"),
+        };
+        if supported {
+            return Ok(());
+        }
+        Err(NatsError::from((
+            ErrorKind::Unsupported,
+            "Compression algorithm not enabled",
+            self.name().to_owned(),
+        )))
+    }
+
+    #[cfg_attr(not(any(feature = "gzip", feature = "zstd")), allow(unused_variables))]
+    fn compress(self, msg: &[u8]) -> Result<Vec<u8>, NatsError> {
+        match self {
+            #[cfg(feature = "gzip")]
+            CompressionAlgorithm::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(msg)?;
+                Ok(encoder.finish()?)
+            }
+            #[cfg(feature = "zstd")]
+            CompressionAlgorithm::Zstd => {
+                let mut encoder = zstd::stream::write::Encoder::new(Vec::new(), 0)?;
+                encoder.write_all(msg)?;
+                Ok(encoder.finish()?)
+            }
+            #[allow(unreachable_patterns)]
+            _ => self.check_supported().map(|_| Vec::new()),
+        }
+    }
+
+    #[cfg_attr(
+        not(any(feature = "gzip", feature = "zstd")),
+        allow(unused_variables, unused_mut, unreachable_code)
+    )]
+    fn decompress(self, msg: &[u8]) -> Result<Vec<u8>, NatsError> {
+        let mut decompressed = Vec::new();
+        let res: std::io::Result<usize> = match self {
+            #[cfg(feature = "gzip")]
+            CompressionAlgorithm::Gzip => {
+                read_limited(flate2::read::GzDecoder::new(msg), &mut decompressed)
+            }
+            #[cfg(feature = "zstd")]
+            CompressionAlgorithm::Zstd => zstd::stream::read::Decoder::new(msg)
+                .and_then(|decoder| read_limited(decoder, &mut decompressed)),
+            #[allow(unreachable_patterns)]
+            _ => return self.check_supported().map(|_| Vec::new()),
+        };
+        res.map_err(|e| {
+            NatsError::from((
+                ErrorKind::DecodeError,
+                "Invalid compressed payload",
+                e.to_string(),
+            ))
+        })?;
+        if decompressed.len() > MAX_DECOMPRESSED_SIZE {
+            return Err(NatsError::from((
+                ErrorKind::DecodeError,
+                "Decompressed payload too large",
+                format!("more than {} bytes", MAX_DECOMPRESSED_SIZE),
+            )));
+        }
+        Ok(decompressed)
+    }
+}
+
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+fn read_limited<R: Read>(reader: R, out: &mut Vec<u8>) -> std::io::Result<usize> {
+    reader
+        .take(MAX_DECOMPRESSED_SIZE as u64 + 1)
+        .read_to_end(out)
+}
+
+// Payloads of at least `threshold` bytes are compressed before being
+// published
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PayloadCompression {
+    pub algorithm: CompressionAlgorithm,
+    pub threshold: usize,
+}
+
+impl PayloadCompression {
+    pub fn new(algorithm: CompressionAlgorithm) -> PayloadCompression {
+        PayloadCompression {
+            algorithm,
+            threshold: DEFAULT_COMPRESSION_THRESHOLD,
+        }
+    }
+
+    // Returns the compressed payload, or `None` if it has to be sent as-is.
+    // Payloads that don't shrink are sent as-is.
+    pub(crate) fn compress(&self, msg: &[u8]) -> Result<Option<Vec<u8>>, NatsError> {
+        if msg.len() < self.threshold {
+            return Ok(None);
+        }
+        let compressed = self.algorithm.compress(msg)?;
+        if compressed.len() >= msg.len() {
+            return Ok(None);
+        }
+        Ok(Some(compressed))
+    }
+}
+
+// Decompresses the payload of a message flagged as compressed, and removes
+// the flag. Messages without the header, or that are still encrypted, are
+// left untouched.
+pub(crate) fn decompress_event(event: &mut Event) -> Result<(), NatsError> {
+    let headers = match event.headers {
+        Some(ref headers) if headers.get(PAYLOAD_ENCRYPTION_HEADER).is_none() => headers,
+        _ => return Ok(()),
+    };
+    let name = match headers.get(PAYLOAD_ENCODING_HEADER) {
+        None => return Ok(()),
+        Some(name) => name,
+    };
+    let algorithm = CompressionAlgorithm::from_name(name).ok_or_else(|| {
+        NatsError::from((
+            ErrorKind::DecodeError,
+            "Unknown payload encoding",
+            format!("{}: {}", event.subject, name),
+        ))
+    })?;
+    event.msg = Bytes::from(algorithm.decompress(&event.msg)?);
+    if let Some(ref mut headers) = event.headers {
+        headers.remove(PAYLOAD_ENCODING_HEADER);
+    }
+    Ok(())
+}
+
+#[cfg(feature = "gzip")]
+#[test]
+fn compression_test() {
+    use crate::client::Channel;
+    use crate::headers::Headers;
+    use std::time::SystemTime;
+
+    let compression = PayloadCompression::new(CompressionAlgorithm::Gzip);
+    assert!(compression.compress(b"short").unwrap().is_none());
+    let payload = b"{\"value\": 42}".repeat(200);
+    let msg = compression.compress(&payload).unwrap().unwrap();
+    assert!(msg.len() < payload.len());
+    let mut headers = Headers::new();
+    headers.set(PAYLOAD_ENCODING_HEADER, "gzip");
+    let mut event = Event {
+        subject: "subject".to_owned(),
+        channel: Channel { sid: 1 },
+        msg: Bytes::from(msg),
+        inbox: None,
+        headers: Some(headers),
+        received_at: SystemTime::now(),
+    };
+    decompress_event(&mut event).unwrap();
+    assert_eq!(event.msg.as_ref(), payload.as_slice());
+    assert!(event
+        .headers
+        .unwrap()
+        .get(PAYLOAD_ENCODING_HEADER)
+        .is_none());
+}
@@ -0,0 +1,234 @@
+use crate::errors::*;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use std::{collections::HashMap, convert::TryInto, fmt};
+
+const SEALED_VERSION: u8 = 1;
+const NONCE_LEN: usize = 24;
+const TAG_LEN: usize = 16;
+pub const KEY_LEN: usize = 32;
+
+// XChaCha20-Poly1305 keys used to encrypt key-value entries and message
+// payloads before they are sent to the server. Values are tagged with the
+// identifier of the key that sealed them, so that older keys can be kept
+// around to read values written before a rotation. Nonces are random 192-bit
+// values, so that a key can seal any number of values.
+pub struct PayloadCipher {
+    current: String,
+    keys: HashMap<String, [u8; KEY_LEN]>,
+}
+
+impl PayloadCipher {
+    pub fn new(key_id: &str, key: [u8; KEY_LEN]) -> Result<PayloadCipher, NatsError> {
+        if key_id.is_empty() || key_id.len() > u8::MAX as usize {
+            return Err(NatsError::from((
+                ErrorKind::InvalidClientConfig,
+                "Key identifiers must be between 1 and 255 bytes long",
+            )));
+        }
+        let mut keys = HashMap::new();
+        keys.insert(key_id.to_owned(), key);
+        Ok(PayloadCipher {
+            current: key_id.to_owned(),
+            keys,
+        })
+    }
+
+    // Adds a key that can only be used to decrypt existing values
+    pub fn add_decryption_key(&mut self, key_id: &str, key: [u8; KEY_LEN]) -> &mut Self {
+        if key_id != self.current {
+            self.keys.insert(key_id.to_owned(), key);
+        }
+        self
+    }
+
+    // Encrypts new values with a different key, keeping the previous one for
+    // decryption
+    pub fn rotate(&mut self, key_id: &str, key: [u8; KEY_LEN]) -> Result<(), NatsError> {
+        let rotated = PayloadCipher::new(key_id, key)?;
+        self.keys.extend(rotated.keys);
+        self.current = rotated.current;
+        Ok(())
+    }
+
+    pub fn current_key_id(&self) -> &str {
+        &self.current
+    }
+
+    // version || key id length || key id || nonce || ciphertext || tag
+    // The key id and the location of the value are authenticated.
+    pub fn seal(&self, location: &str, value: &[u8]) -> Result<Vec<u8>, NatsError> {
+        let key = &self.keys[&self.current];
+        let mut nonce = [0u8; NONCE_LEN];
+        openssl::rand::rand_bytes(&mut nonce)?;
+        let aad = Self::aad(location, &self.current);
+        let mut tag = [0u8; TAG_LEN];
+        let (subkey, chacha_nonce) = xchacha20_subkey(key, &nonce);
+        let ciphertext = encrypt_aead(
+            Cipher::chacha20_poly1305(),
+            &subkey,
+            Some(&chacha_nonce),
+            &aad,
+            value,
+            &mut tag,
+        )?;
+        let mut sealed =
+            Vec::with_capacity(2 + self.current.len() + NONCE_LEN + ciphertext.len() + TAG_LEN);
+        sealed.push(SEALED_VERSION);
+        sealed.push(self.current.len() as u8);
+        sealed.extend_from_slice(self.current.as_bytes());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        sealed.extend_from_slice(&tag);
+        Ok(sealed)
+    }
+
+    pub fn open(&self, location: &str, sealed: &[u8]) -> Result<Vec<u8>, NatsError> {
+        let invalid = || NatsError::from((ErrorKind::DecodeError, "Invalid encrypted value"));
+        if sealed.len() < 2 || sealed[0] != SEALED_VERSION {
+            return Err(invalid());
+        }
+        let key_id_len = sealed[1] as usize;
+        if sealed.len() < 2 + key_id_len + NONCE_LEN + TAG_LEN {
+            return Err(invalid());
+        }
+        let (key_id, rest) = sealed[2..].split_at(key_id_len);
+        let key_id = std::str::from_utf8(key_id)?;
+        let key = self.keys.get(key_id).ok_or_else(|| {
+            NatsError::from((
+                ErrorKind::DecodeError,
+                "Value encrypted with an unknown key",
+                key_id.to_owned(),
+            ))
+        })?;
+        let (nonce, rest) = rest.split_at(NONCE_LEN);
+        let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+        let (subkey, chacha_nonce) = xchacha20_subkey(key, nonce);
+        decrypt_aead(
+            Cipher::chacha20_poly1305(),
+            &subkey,
+            Some(&chacha_nonce),
+            &Self::aad(location, key_id),
+            ciphertext,
+            tag,
+        )
+        .map_err(|_| {
+            NatsError::from((
+                ErrorKind::IntegrityError,
+                "Unable to decrypt the value",
+                location.to_owned(),
+            ))
+        })
+    }
+
+    fn aad(location: &str, key_id: &str) -> Vec<u8> {
+        let mut aad = Vec::with_capacity(location.len() + 1 + key_id.len());
+        aad.extend_from_slice(location.as_bytes());
+        aad.push(0);
+        aad.extend_from_slice(key_id.as_bytes());
+        aad
+    }
+}
+
+impl fmt::Debug for PayloadCipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PayloadCipher {{ current: {:?} }}", self.current)
+    }
+}
+
+// XChaCha20-Poly1305 is ChaCha20-Poly1305 with a subkey derived from the
+// first 16 bytes of the nonce by HChaCha20, and the last 8 bytes of the nonce
+// (draft-irtf-cfrg-xchacha). OpenSSL only provides the latter.
+fn xchacha20_subkey(key: &[u8; KEY_LEN], nonce: &[u8]) -> ([u8; KEY_LEN], [u8; 12]) {
+    let word = |bytes: &[u8]| u32::from_le_bytes(bytes.try_into().unwrap());
+    let mut s = [0u32; 16];
+    s[..4].copy_from_slice(&[0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574]);
+    for i in 0..8 {
+        s[4 + i] = word(&key[4 * i..4 * i + 4]);
+    }
+    for i in 0..4 {
+        s[12 + i] = word(&nonce[4 * i..4 * i + 4]);
+    }
+    for _ in 0..10 {
+        for &(a, b, c, d) in &[
+            (0, 4, 8, 12),
+            (1, 5, 9, 13),
+            (2, 6, 10, 14),
+            (3, 7, 11, 15),
+            (0, 5, 10, 15),
+            (1, 6, 11, 12),
+            (2, 7, 8, 13),
+            (3, 4, 9, 14),
+        ] {
+            s[a] = s[a].wrapping_add(s[b]);
+            s[d] = (s[d] ^ s[a]).rotate_left(16);
+            s[c] = s[c].wrapping_add(s[d]);
+            s[b] = (s[b] ^ s[c]).rotate_left(12);
+            s[a] = s[a].wrapping_add(s[b]);
+            s[d] = (s[d] ^ s[a]).rotate_left(8);
+            s[c] = s[c].wrapping_add(s[d]);
+            s[b] = (s[b] ^ s[c]).rotate_left(7);
+        }
+    }
+    let mut subkey = [0u8; KEY_LEN];
+    for (i, w) in s[..4].iter().chain(&s[12..]).enumerate() {
+        subkey[4 * i..4 * i + 4].copy_from_slice(&w.to_le_bytes());
+    }
+    let mut chacha_nonce = [0u8; 12];
+    chacha_nonce[4..].copy_from_slice(&nonce[16..NONCE_LEN]);
+    (subkey, chacha_nonce)
+}
+
+#[test]
+fn xchacha20_poly1305_test() {
+    // Test vector from draft-irtf-cfrg-xchacha, appendix A.3.1
+    let hex = |s: &str| -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    };
+    let mut key = [0u8; KEY_LEN];
+    key.copy_from_slice(&hex(
+        "808182838485868788898a8b8c8d8e8f909192939495969798999a9b9c9d9e9f",
+    ));
+    let nonce = hex("404142434445464748494a4b4c4d4e4f5051525354555657");
+    let (subkey, chacha_nonce) = xchacha20_subkey(&key, &nonce);
+    let mut tag = [0u8; TAG_LEN];
+    let ciphertext = encrypt_aead(
+        Cipher::chacha20_poly1305(),
+        &subkey,
+        Some(&chacha_nonce),
+        &hex("50515253c0c1c2c3c4c5c6c7"),
+        b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.",
+        &mut tag,
+    )
+    .unwrap();
+    assert_eq!(
+        &ciphertext[..16],
+        &hex("bd6d179d3e83d43b9576579493c0e939")[..]
+    );
+    assert_eq!(&tag[..], &hex("c0875924c1c7987947deafd8780acf49")[..]);
+}
+
+#[test]
+fn payload_cipher_test() {
+    let mut cipher = PayloadCipher::new("k1", [1; KEY_LEN]).unwrap();
+    let sealed = cipher.seal("$KV.secrets.db", b"hunter2").unwrap();
+    assert_eq!(cipher.open("$KV.secrets.db", &sealed).unwrap(), b"hunter2");
+    assert_eq!(
+        cipher.open("$KV.secrets.api", &sealed).unwrap_err().kind(),
+        ErrorKind::IntegrityError
+    );
+
+    cipher.rotate("k2", [2; KEY_LEN]).unwrap();
+    assert_eq!(cipher.current_key_id(), "k2");
+    assert_eq!(cipher.open("$KV.secrets.db", &sealed).unwrap(), b"hunter2");
+    let resealed = cipher.seal("$KV.secrets.db", b"hunter2").unwrap();
+    assert_eq!(&resealed[2..4], b"k2");
+
+    let other = PayloadCipher::new("k2", [2; KEY_LEN]).unwrap();
+    assert_eq!(
+        other.open("$KV.secrets.db", &sealed).unwrap_err().kind(),
+        ErrorKind::DecodeError
+    );
+}
@@ -0,0 +1,137 @@
+use crate::client::Event;
+use crate::crypto::PayloadCipher;
+use crate::errors::*;
+use bytes::Bytes;
+use std::{fmt, sync::Arc};
+
+pub const PAYLOAD_ENCRYPTION_HEADER: &str = "Nats-Payload-Encryption";
+const PAYLOAD_ENCRYPTION: &str = "xchacha20-poly1305";
+
+// Returns the keys used to encrypt and decrypt the payloads of messages sent
+// to a subject, or `None` if they are not encrypted
+pub trait KeyLookup: Send {
+    fn lookup(&mut self, subject: &str) -> Option<Arc<PayloadCipher>>;
+}
+
+impl<F: FnMut(&str) -> Option<Arc<PayloadCipher>> + Send> KeyLookup for F {
+    fn lookup(&mut self, subject: &str) -> Option<Arc<PayloadCipher>> {
+        self(subject)
+    }
+}
+
+impl fmt::Debug for dyn KeyLookup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "KeyLookup {{}}")
+    }
+}
+
+// Encrypts a payload, bound to the subject it is published to
+pub(crate) fn encrypt(
+    cipher: &PayloadCipher,
+    subject: &str,
+    msg: &[u8],
+) -> Result<(Vec<u8>, &'static str), NatsError> {
+    Ok((cipher.seal(subject, msg)?, PAYLOAD_ENCRYPTION))
+}
+
+// Decrypts the payload of a received message, and removes the flag.
+// Plaintext messages are rejected on subjects that have a key, as anyone
+// with access to the server could have published them.
+pub(crate) fn decrypt_event(
+    lookup: &mut dyn KeyLookup,
+    event: &mut Event,
+) -> Result<(), NatsError> {
+    // Sent by the server itself, so that requests fail without a timeout
+    if event.is_no_responders() {
+        return Ok(());
+    }
+    let encryption = event
+        .headers
+        .as_ref()
+        .and_then(|headers| headers.get(PAYLOAD_ENCRYPTION_HEADER));
+    let cipher = lookup.lookup(&event.subject);
+    let cipher = match (encryption, cipher) {
+        (None, None) => return Ok(()),
+        (None, Some(_)) => {
+            return Err(NatsError::from((
+                ErrorKind::IntegrityError,
+                "Unencrypted payload received on an encrypted subject",
+                event.subject.clone(),
+            )))
+        }
+        (Some(encryption), _) if encryption != PAYLOAD_ENCRYPTION => {
+            return Err(NatsError::from((
+                ErrorKind::DecodeError,
+                "Unknown payload encryption",
+                format!("{}: {}", event.subject, encryption),
+            )))
+        }
+        (Some(_), None) => {
+            return Err(NatsError::from((
+                ErrorKind::DecodeError,
+                "No key to decrypt the payload",
+                event.subject.clone(),
+            )))
+        }
+        (Some(_), Some(cipher)) => cipher,
+    };
+    event.msg = Bytes::from(cipher.open(&event.subject, &event.msg)?);
+    if let Some(ref mut headers) = event.headers {
+        headers.remove(PAYLOAD_ENCRYPTION_HEADER);
+    }
+    Ok(())
+}
+
+#[test]
+fn payload_encryption_test() {
+    use crate::client::Channel;
+    use crate::crypto::KEY_LEN;
+    use crate::headers::Headers;
+    use std::time::SystemTime;
+
+    let cipher = Arc::new(PayloadCipher::new("k1", [7u8; KEY_LEN]).unwrap());
+    let (sealed, encryption) = encrypt(&cipher, "orders.new", b"secret").unwrap();
+    let mut headers = Headers::new();
+    headers.set(PAYLOAD_ENCRYPTION_HEADER, encryption);
+    let event = Event {
+        subject: "orders.new".to_owned(),
+        channel: Channel { sid: 1 },
+        msg: Bytes::from(sealed),
+        inbox: None,
+        headers: Some(headers),
+        received_at: SystemTime::now(),
+    };
+    let mut lookup = move |subject: &str| {
+        if subject.starts_with("orders.") {
+            Some(cipher.clone())
+        } else {
+            None
+        }
+    };
+
+    let mut decrypted = event.clone();
+    decrypt_event(&mut lookup, &mut decrypted).unwrap();
+    assert_eq!(decrypted.msg.as_ref(), b"secret");
+
+    // Replayed to another subject
+    let mut replayed = event.clone();
+    replayed.subject = "orders.cancel".to_owned();
+    let e = decrypt_event(&mut lookup, &mut replayed).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::IntegrityError);
+
+    let mut plaintext = event.clone();
+    plaintext.headers = None;
+    let e = decrypt_event(&mut lookup, &mut plaintext).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::IntegrityError);
+
+    let mut undecryptable = event.clone();
+    undecryptable.subject = "payments.new".to_owned();
+    let e = decrypt_event(&mut lookup, &mut undecryptable).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::DecodeError);
+
+    let mut no_responders = event;
+    no_responders.msg = Bytes::new();
+    no_responders.headers = Some(Headers::decode(b"NATS/1.0 503\r\n\r\n").unwrap());
+    decrypt_event(&mut lookup, &mut no_responders).unwrap();
+    assert!(no_responders.is_no_responders());
+}
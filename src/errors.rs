@@ -1,13 +1,28 @@
 #![allow(dead_code)]
 
-use openssl;
-use url;
-
 use std::error::Error;
 use std::fmt;
 use std::io;
 use std::str::Utf8Error;
 
+// Receives errors that can't be returned to the caller of the operation
+// that caused them
+pub trait ErrorCallback: Send {
+    fn call(&mut self, error: &NatsError);
+}
+
+impl<F: FnMut(&NatsError) + Send> ErrorCallback for F {
+    fn call(&mut self, error: &NatsError) {
+        self(error)
+    }
+}
+
+impl fmt::Debug for dyn ErrorCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ErrorCallback {{}}")
+    }
+}
+
 #[derive(PartialEq, Eq, Copy, Clone, Debug)]
 pub enum ErrorKind {
     ClientProtocolError,
@@ -17,93 +32,266 @@ pub enum ErrorKind {
     ServerProtocolError,
     TypeError,
     TlsError,
+    DecodeError,
+    Timeout,
+    IntegrityError,
+    ConnectionClosed,
+    SlowConsumer,
+    Unsupported,
+    NoResponders,
+    AuthorizationViolation,
+    PermissionViolation,
+    WouldBlock,
+    HandlerPanicked,
+}
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum PermissionOperation {
+    Publish,
+    Subscribe,
 }
 
+// What went wrong, with the data available to handle the error. New variants
+// may be added, so matches need a wildcard arm.
 #[derive(Debug)]
-enum ErrorRepr {
-    WithDescription(ErrorKind, &'static str),
-    WithDescriptionAndDetail(ErrorKind, &'static str, String),
-    IoError(io::Error),
-    UrlParseError(url::ParseError),
+#[non_exhaustive]
+pub enum ErrorInfo {
+    Io(io::Error),
+    Tls(openssl::error::ErrorStack),
+    UrlParse(url::ParseError),
+    // Invalid JSON received from the server or in a payload, or a value
+    // that couldn't be encoded
+    Json {
+        kind: ErrorKind,
+        description: &'static str,
+        error: serde_json::Error,
+    },
+    // Authentication rejected by the server
+    Auth {
+        message: String,
+    },
+    // Publication or subscription denied by the permissions of the user.
+    // The connection stays open.
+    PermissionViolation {
+        operation: PermissionOperation,
+        subject: String,
+        message: String,
+    },
+    // `-ERR` sent by the server
+    ServerError {
+        message: String,
+    },
+    Timeout {
+        description: &'static str,
+        detail: Option<String>,
+    },
+    MaxPayloadExceeded {
+        size: usize,
+        max_payload: usize,
+    },
+    ConnectionClosed {
+        description: &'static str,
+        detail: Option<String>,
+    },
+    // Errors only described by their kind and a message
+    Other {
+        kind: ErrorKind,
+        description: &'static str,
+        detail: Option<String>,
+    },
 }
 
 #[derive(Debug)]
 pub struct NatsError {
-    repr: ErrorRepr,
+    info: ErrorInfo,
+    connection: Option<String>,
 }
 
 impl NatsError {
     pub fn kind(&self) -> ErrorKind {
-        match self.repr {
-            ErrorRepr::WithDescription(kind, _)
-            | ErrorRepr::WithDescriptionAndDetail(kind, _, _) => kind,
-            ErrorRepr::IoError(_) => ErrorKind::IoError,
-            ErrorRepr::UrlParseError(_) => ErrorKind::InvalidSchemeError,
+        match self.info {
+            ErrorInfo::Io(_) => ErrorKind::IoError,
+            ErrorInfo::Tls(_) => ErrorKind::TlsError,
+            ErrorInfo::UrlParse(_) => ErrorKind::InvalidSchemeError,
+            ErrorInfo::Json { kind, .. } => kind,
+            ErrorInfo::Auth { .. } => ErrorKind::AuthorizationViolation,
+            ErrorInfo::PermissionViolation { .. } => ErrorKind::PermissionViolation,
+            ErrorInfo::ServerError { .. } => ErrorKind::ServerProtocolError,
+            ErrorInfo::Timeout { .. } => ErrorKind::Timeout,
+            ErrorInfo::MaxPayloadExceeded { .. } => ErrorKind::ClientProtocolError,
+            ErrorInfo::ConnectionClosed { .. } => ErrorKind::ConnectionClosed,
+            ErrorInfo::Other { kind, .. } => kind,
         }
     }
+
+    pub fn info(&self) -> &ErrorInfo {
+        &self.info
+    }
+
+    // Label of the connection the error occurred on, if any
+    pub fn connection(&self) -> Option<&str> {
+        self.connection.as_deref()
+    }
+
+    // Keeps the innermost label if the error was already labeled
+    pub(crate) fn with_connection(mut self, label: &str) -> NatsError {
+        if self.connection.is_none() {
+            self.connection = Some(label.to_owned());
+        }
+        self
+    }
 }
 
-impl Error for NatsError {}
+impl Error for NatsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self.info {
+            ErrorInfo::Io(ref e) => Some(e),
+            ErrorInfo::Tls(ref e) => Some(e),
+            ErrorInfo::UrlParse(ref e) => Some(e),
+            ErrorInfo::Json { ref error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
 
 impl fmt::Display for NatsError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
-        match self.repr {
-            ErrorRepr::WithDescription(_, description) => description.fmt(f),
-            ErrorRepr::WithDescriptionAndDetail(_, description, ref detail) => {
+        match self.info {
+            ErrorInfo::Io(ref e) => e.fmt(f)?,
+            ErrorInfo::Tls(ref e) => write!(f, "TLS error: {}", e)?,
+            ErrorInfo::UrlParse(ref e) => e.fmt(f)?,
+            ErrorInfo::Json {
+                description,
+                ref error,
+                ..
+            } => write!(f, "{}: {}", description, error)?,
+            ErrorInfo::Auth { ref message } => write!(f, "Authentication failed: {}", message)?,
+            ErrorInfo::PermissionViolation { ref message, .. }
+            | ErrorInfo::ServerError { ref message } => write!(f, "Server error: {}", message)?,
+            ErrorInfo::MaxPayloadExceeded { size, max_payload } => write!(
+                f,
+                "Message too large: {} bytes, maximum payload size is {} bytes",
+                size, max_payload
+            )?,
+            ErrorInfo::Timeout {
+                description,
+                ref detail,
+            }
+            | ErrorInfo::ConnectionClosed {
+                description,
+                ref detail,
+            }
+            | ErrorInfo::Other {
+                description,
+                ref detail,
+                ..
+            } => {
                 description.fmt(f)?;
-                f.write_str(": ")?;
-                detail.fmt(f)
+                if let Some(ref detail) = *detail {
+                    f.write_str(": ")?;
+                    detail.fmt(f)?
+                }
             }
-            ErrorRepr::IoError(ref e) => e.fmt(f),
-            ErrorRepr::UrlParseError(ref e) => e.fmt(f),
         }
+        if let Some(ref connection) = self.connection {
+            write!(f, " [{}]", connection)?;
+        }
+        Ok(())
     }
 }
 
 impl From<Utf8Error> for NatsError {
     fn from(_: Utf8Error) -> NatsError {
+        NatsError::from((ErrorKind::TypeError, "Invalid UTF-8"))
+    }
+}
+
+impl From<ErrorInfo> for NatsError {
+    fn from(info: ErrorInfo) -> NatsError {
         NatsError {
-            repr: ErrorRepr::WithDescription(ErrorKind::TypeError, "Invalid UTF-8"),
+            info,
+            connection: None,
         }
     }
 }
 
+impl From<(ErrorKind, &'static str, Option<String>)> for NatsError {
+    fn from((kind, description, detail): (ErrorKind, &'static str, Option<String>)) -> NatsError {
+        NatsError::from(match kind {
+            ErrorKind::Timeout => ErrorInfo::Timeout {
+                description,
+                detail,
+            },
+            ErrorKind::ConnectionClosed => ErrorInfo::ConnectionClosed {
+                description,
+                detail,
+            },
+            _ => ErrorInfo::Other {
+                kind,
+                description,
+                detail,
+            },
+        })
+    }
+}
+
 impl From<(ErrorKind, &'static str)> for NatsError {
     fn from((kind, description): (ErrorKind, &'static str)) -> NatsError {
-        NatsError {
-            repr: ErrorRepr::WithDescription(kind, description),
-        }
+        NatsError::from((kind, description, None))
     }
 }
 
 impl From<(ErrorKind, &'static str, String)> for NatsError {
     fn from((kind, description, detail): (ErrorKind, &'static str, String)) -> NatsError {
-        NatsError {
-            repr: ErrorRepr::WithDescriptionAndDetail(kind, description, detail),
-        }
+        NatsError::from((kind, description, Some(detail)))
+    }
+}
+
+impl From<(ErrorKind, &'static str, serde_json::Error)> for NatsError {
+    fn from((kind, description, error): (ErrorKind, &'static str, serde_json::Error)) -> NatsError {
+        NatsError::from(ErrorInfo::Json {
+            kind,
+            description,
+            error,
+        })
     }
 }
 
 impl From<io::Error> for NatsError {
     fn from(e: io::Error) -> NatsError {
-        NatsError {
-            repr: ErrorRepr::IoError(e),
-        }
+        NatsError::from(ErrorInfo::Io(e))
     }
 }
 
 impl From<openssl::error::ErrorStack> for NatsError {
     fn from(e: openssl::error::ErrorStack) -> NatsError {
-        NatsError {
-            repr: ErrorRepr::WithDescriptionAndDetail(ErrorKind::TlsError, "", e.to_string()),
-        }
+        NatsError::from(ErrorInfo::Tls(e))
     }
 }
 
 impl From<url::ParseError> for NatsError {
     fn from(e: url::ParseError) -> NatsError {
-        NatsError {
-            repr: ErrorRepr::UrlParseError(e),
+        NatsError::from(ErrorInfo::UrlParse(e))
+    }
+}
+
+#[test]
+fn connection_closed_test() {
+    let e = NatsError::from((
+        ErrorKind::ConnectionClosed,
+        "The pool was closed",
+        "member 2".to_owned(),
+    ));
+    assert_eq!(e.kind(), ErrorKind::ConnectionClosed);
+    assert_eq!(e.to_string(), "The pool was closed: member 2");
+    match e.info() {
+        ErrorInfo::ConnectionClosed {
+            description,
+            detail,
+        } => {
+            assert_eq!(*description, "The pool was closed");
+            assert_eq!(detail.as_deref(), Some("member 2"));
         }
+        info => panic!("unexpected error: {:?}", info),
     }
 }
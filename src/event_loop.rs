@@ -0,0 +1,49 @@
+use crate::stream::Stream;
+use mio::{unix::SourceFd, Interest, Registry, Token};
+use std::{io, net::TcpStream, os::unix::io::AsRawFd};
+
+// Registration of a client's socket with a mio `Registry`. The socket changes
+// every time the client reconnects, so the registration keeps a handle on the
+// current one, to move the registration to the next socket.
+#[derive(Debug)]
+pub(crate) struct EventLoopRegistration {
+    registry: Registry,
+    token: Token,
+    socket: Option<TcpStream>,
+}
+
+impl EventLoopRegistration {
+    pub(crate) fn new(registry: &Registry, token: Token) -> io::Result<EventLoopRegistration> {
+        Ok(EventLoopRegistration {
+            registry: registry.try_clone()?,
+            token,
+            socket: None,
+        })
+    }
+
+    pub(crate) fn attach(&mut self, stream: &Stream) -> io::Result<()> {
+        self.detach();
+        let socket = stream.as_tcp()?;
+        self.registry.register(
+            &mut SourceFd(&socket.as_raw_fd()),
+            self.token,
+            Interest::READABLE | Interest::WRITABLE,
+        )?;
+        self.socket = Some(socket);
+        Ok(())
+    }
+
+    // The previous socket may already be closed, which removed it from the
+    // registry anyway
+    pub(crate) fn detach(&mut self) {
+        if let Some(socket) = self.socket.take() {
+            let _ = self.registry.deregister(&mut SourceFd(&socket.as_raw_fd()));
+        }
+    }
+}
+
+impl Drop for EventLoopRegistration {
+    fn drop(&mut self) {
+        self.detach();
+    }
+}
@@ -0,0 +1,167 @@
+use crate::client::{Channel, Event, Subscription};
+use crate::errors::*;
+use crate::shared::{EventSender, SharedClient, SlowConsumerPolicy};
+use futures_core::Stream;
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    sync::{mpsc::TrySendError, Arc, Mutex},
+    task::{Context, Poll, Waker},
+};
+
+const DEFAULT_STREAM_BOUND: usize = 65536;
+
+// Events of a subscription forwarded by the reader thread, for async
+// applications. The stream ends once the subscription is removed.
+#[derive(Debug)]
+pub struct EventStream {
+    queue: Arc<Mutex<StreamQueue>>,
+}
+
+#[derive(Debug, Default)]
+struct StreamQueue {
+    events: VecDeque<Event>,
+    bound: usize,
+    waker: Option<Waker>,
+    stream_dropped: bool,
+    sender_dropped: bool,
+}
+
+#[derive(Debug)]
+struct StreamSender {
+    queue: Arc<Mutex<StreamQueue>>,
+}
+
+impl EventSender for StreamSender {
+    fn try_send(&self, event: Event) -> Result<(), TrySendError<Event>> {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.stream_dropped {
+            return Err(TrySendError::Disconnected(event));
+        }
+        if queue.events.len() >= queue.bound {
+            return Err(TrySendError::Full(event));
+        }
+        queue.events.push_back(event);
+        if let Some(waker) = queue.waker.take() {
+            waker.wake();
+        }
+        Ok(())
+    }
+}
+
+impl Drop for StreamSender {
+    fn drop(&mut self) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.sender_dropped = true;
+        if let Some(waker) = queue.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl Stream for EventStream {
+    type Item = Event;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Event>> {
+        let mut queue = self.queue.lock().unwrap();
+        if let Some(event) = queue.events.pop_front() {
+            return Poll::Ready(Some(event));
+        }
+        if queue.sender_dropped {
+            return Poll::Ready(None);
+        }
+        queue.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Drop for EventStream {
+    fn drop(&mut self) {
+        self.queue.lock().unwrap().stream_dropped = true;
+    }
+}
+
+fn event_stream(
+    shared: &SharedClient,
+    channel: Channel,
+    bound: usize,
+    policy: SlowConsumerPolicy,
+) -> EventStream {
+    let queue = Arc::new(Mutex::new(StreamQueue {
+        bound,
+        ..StreamQueue::default()
+    }));
+    let sender = StreamSender {
+        queue: queue.clone(),
+    };
+    shared.route_to_channel(channel, Arc::new(sender), policy);
+    EventStream { queue }
+}
+
+impl SharedClient {
+    // Subscribes, and returns a stream of the events of the subscription,
+    // forwarded by the reader thread. Up to 65536 events not consumed yet
+    // are kept; the next ones are dropped.
+    pub fn subscribe_stream(
+        &self,
+        subject: &str,
+        queue: Option<&str>,
+    ) -> Result<(Channel, EventStream), NatsError> {
+        let channel = self.subscribe(subject, queue)?;
+        let stream = event_stream(
+            self,
+            channel,
+            DEFAULT_STREAM_BOUND,
+            SlowConsumerPolicy::Drop,
+        );
+        Ok((channel, stream))
+    }
+}
+
+impl Subscription {
+    // Same as `into_receiver()`, returning a stream
+    pub fn into_stream(
+        self,
+        shared: &SharedClient,
+        bound: usize,
+        policy: SlowConsumerPolicy,
+    ) -> EventStream {
+        event_stream(shared, self.detach(), bound, policy)
+    }
+}
+
+#[test]
+fn event_stream_test() {
+    use bytes::Bytes;
+    use std::time::SystemTime;
+
+    let queue = Arc::new(Mutex::new(StreamQueue {
+        bound: 1,
+        ..StreamQueue::default()
+    }));
+    let sender = StreamSender {
+        queue: queue.clone(),
+    };
+    let mut stream = EventStream { queue };
+    let mut cx = Context::from_waker(Waker::noop());
+    assert!(Pin::new(&mut stream).poll_next(&mut cx).is_pending());
+    let event = Event {
+        subject: "subject".to_owned(),
+        channel: Channel { sid: 1 },
+        msg: Bytes::from_static(b"test"),
+        inbox: None,
+        headers: None,
+        received_at: SystemTime::now(),
+    };
+    sender.try_send(event.clone()).unwrap();
+    assert!(matches!(sender.try_send(event), Err(TrySendError::Full(_))));
+    assert!(matches!(
+        Pin::new(&mut stream).poll_next(&mut cx),
+        Poll::Ready(Some(_))
+    ));
+    drop(sender);
+    assert!(matches!(
+        Pin::new(&mut stream).poll_next(&mut cx),
+        Poll::Ready(None)
+    ));
+}
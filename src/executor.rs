@@ -0,0 +1,464 @@
+use crate::client::Event;
+use crate::errors::*;
+use crate::shared::EventSender;
+use log::warn;
+use std::{
+    collections::{hash_map::DefaultHasher, VecDeque},
+    fmt,
+    hash::{Hash, Hasher},
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Sender, TrySendError},
+        Arc, Condvar, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+const MAX_QUEUED_HANDLER_EVENTS: usize = 65536;
+
+// Processes events on a pool of threads. Events mapped to the same key by the
+// key extractor are processed sequentially, in the order they were
+// submitted; events with different keys can be processed in parallel.
+pub struct OrderedExecutor {
+    workers: Vec<Worker>,
+    key: Box<dyn Fn(&Event) -> u64 + Send>,
+    panics: Arc<AtomicU64>,
+}
+
+struct Worker {
+    tx: Option<Sender<Event>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl OrderedExecutor {
+    pub fn new<K, KF, H>(threads: usize, key: KF, handler: H) -> OrderedExecutor
+    where
+        K: Hash,
+        KF: Fn(&Event) -> K + Send + 'static,
+        H: Fn(Event) + Send + Sync + 'static,
+    {
+        let handler = Arc::new(handler);
+        let panics = Arc::new(AtomicU64::new(0));
+        let workers = (0..threads.max(1))
+            .map(|_| {
+                let (tx, rx) = mpsc::channel::<Event>();
+                let handler = handler.clone();
+                let panics = panics.clone();
+                // A panic only skips the event being handled, not the
+                // following ones
+                let thread = thread::spawn(move || {
+                    for event in rx {
+                        if !run_handler(&*handler, event) {
+                            panics.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                });
+                Worker {
+                    tx: Some(tx),
+                    thread: Some(thread),
+                }
+            })
+            .collect();
+        OrderedExecutor {
+            workers,
+            key: Box::new(move |event| {
+                let mut hasher = DefaultHasher::new();
+                key(event).hash(&mut hasher);
+                hasher.finish()
+            }),
+            panics,
+        }
+    }
+
+    // Queues an event for the thread in charge of its key
+    pub fn submit(&self, event: Event) {
+        let idx = ((self.key)(&event) % self.workers.len() as u64) as usize;
+        if let Some(ref tx) = self.workers[idx].tx {
+            let _ = tx.send(event);
+        }
+    }
+
+    // Submits events until the iterator ends, for example
+    // `client.events()` or `receiver.iter()`
+    pub fn run<I: IntoIterator<Item = Event>>(&self, events: I) {
+        for event in events {
+            self.submit(event);
+        }
+    }
+
+    // Waits for the queued events to be processed, and stops the threads.
+    // Returns an error if handlers panicked.
+    pub fn shutdown(mut self) -> Result<(), NatsError> {
+        self.stop();
+        handler_panics(self.panics.load(Ordering::Relaxed))
+    }
+
+    fn stop(&mut self) {
+        for worker in &mut self.workers {
+            worker.tx = None;
+        }
+        for worker in &mut self.workers {
+            if let Some(thread) = worker.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+impl Drop for OrderedExecutor {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+impl fmt::Debug for OrderedExecutor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "OrderedExecutor {{ threads: {} }}", self.workers.len())
+    }
+}
+
+// Threads running the handlers of subscriptions created with
+// `SharedClient::subscribe_with_handler()`. Subscriptions are processed
+// concurrently, and each of them has at most `max_in_flight` events being
+// handled at a time: with 1, its events are handled in order.
+pub struct WorkerPool {
+    shared: Arc<PoolShared>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+struct PoolShared {
+    // Subscriptions allowed to have one more event handled
+    ready: Mutex<PoolQueue>,
+    available: Condvar,
+    panics: AtomicU64,
+}
+
+#[derive(Default)]
+struct PoolQueue {
+    dispatchers: VecDeque<Arc<DispatcherQueue>>,
+    stopping: bool,
+}
+
+struct DispatcherQueue {
+    state: Mutex<DispatcherState>,
+    max_in_flight: usize,
+    handler: Box<dyn Fn(Event) + Send + Sync>,
+}
+
+#[derive(Default)]
+struct DispatcherState {
+    events: VecDeque<Event>,
+    in_flight: usize,
+}
+
+// Queues the events of a subscription for the worker pool
+pub(crate) struct Dispatcher {
+    queue: Arc<DispatcherQueue>,
+    pool: Arc<PoolShared>,
+}
+
+impl WorkerPool {
+    pub fn new(threads: usize) -> WorkerPool {
+        let shared = Arc::new(PoolShared {
+            ready: Mutex::new(PoolQueue::default()),
+            available: Condvar::new(),
+            panics: AtomicU64::new(0),
+        });
+        let threads = (0..threads.max(1))
+            .map(|_| {
+                let shared = shared.clone();
+                thread::spawn(move || shared.work())
+            })
+            .collect();
+        WorkerPool { shared, threads }
+    }
+
+    pub(crate) fn dispatcher<H>(&self, max_in_flight: usize, handler: H) -> Dispatcher
+    where
+        H: Fn(Event) + Send + Sync + 'static,
+    {
+        Dispatcher {
+            queue: Arc::new(DispatcherQueue {
+                state: Mutex::new(DispatcherState::default()),
+                max_in_flight: max_in_flight.max(1),
+                handler: Box::new(handler),
+            }),
+            pool: self.shared.clone(),
+        }
+    }
+
+    // Waits for the queued events to be handled, and stops the threads.
+    // Subscriptions dispatching to the pool are removed with their next
+    // event. Returns an error if handlers panicked.
+    pub fn shutdown(mut self) -> Result<(), NatsError> {
+        self.stop();
+        handler_panics(self.shared.panics.load(Ordering::Relaxed))
+    }
+
+    fn stop(&mut self) {
+        self.shared.ready.lock().unwrap().stopping = true;
+        self.shared.available.notify_all();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl PoolShared {
+    fn work(&self) {
+        loop {
+            let queue = {
+                let mut ready = self.ready.lock().unwrap();
+                loop {
+                    if let Some(queue) = ready.dispatchers.pop_front() {
+                        break queue;
+                    }
+                    if ready.stopping {
+                        return;
+                    }
+                    ready = self.available.wait(ready).unwrap();
+                }
+            };
+            let event = queue.state.lock().unwrap().events.pop_front();
+            if let Some(event) = event {
+                if !run_handler(&*queue.handler, event) {
+                    self.panics.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            let mut state = queue.state.lock().unwrap();
+            if state.events.is_empty() {
+                state.in_flight -= 1;
+            } else {
+                drop(state);
+                self.schedule(queue);
+            }
+        }
+    }
+
+    fn schedule(&self, queue: Arc<DispatcherQueue>) {
+        self.ready.lock().unwrap().dispatchers.push_back(queue);
+        self.available.notify_one();
+    }
+}
+
+impl EventSender for Dispatcher {
+    fn try_send(&self, event: Event) -> Result<(), TrySendError<Event>> {
+        if self.pool.ready.lock().unwrap().stopping {
+            return Err(TrySendError::Disconnected(event));
+        }
+        let mut state = self.queue.state.lock().unwrap();
+        if state.events.len() >= MAX_QUEUED_HANDLER_EVENTS {
+            return Err(TrySendError::Full(event));
+        }
+        state.events.push_back(event);
+        if state.in_flight < self.queue.max_in_flight {
+            state.in_flight += 1;
+            drop(state);
+            self.pool.schedule(self.queue.clone());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+// Runs a handler, recovering from a panic so that the thread can handle the
+// next events. Returns `false` if the handler panicked.
+fn run_handler<H: Fn(Event) + ?Sized>(handler: &H, event: Event) -> bool {
+    let subject = event.subject.clone();
+    match panic::catch_unwind(AssertUnwindSafe(|| handler(event))) {
+        Ok(()) => true,
+        Err(_) => {
+            warn!(
+                "Event handler panicked while handling a message on [{}]",
+                subject
+            );
+            false
+        }
+    }
+}
+
+fn handler_panics(panics: u64) -> Result<(), NatsError> {
+    if panics == 0 {
+        return Ok(());
+    }
+    Err(NatsError::from((
+        ErrorKind::HandlerPanicked,
+        "Event handlers panicked",
+        format!("{} events", panics),
+    )))
+}
+
+impl fmt::Debug for WorkerPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "WorkerPool {{ threads: {} }}", self.threads.len())
+    }
+}
+
+impl fmt::Debug for Dispatcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Dispatcher {{ max_in_flight: {} }}",
+            self.queue.max_in_flight
+        )
+    }
+}
+
+#[test]
+fn ordered_executor_test() {
+    use crate::client::Channel;
+    use bytes::Bytes;
+    use std::sync::Mutex;
+    use std::time::{Duration, SystemTime};
+
+    let processed = Arc::new(Mutex::new(Vec::new()));
+    let processed_ = processed.clone();
+    let executor = OrderedExecutor::new(
+        4,
+        |event: &Event| event.subject.clone(),
+        move |event: Event| {
+            if event.msg[0] == b'0' {
+                thread::sleep(Duration::from_millis(20));
+            }
+            processed_
+                .lock()
+                .unwrap()
+                .push((event.subject.clone(), event.msg[0]));
+        },
+    );
+    let events = (0..30u8).map(|i| Event {
+        subject: format!("entity.{}", i % 3),
+        channel: Channel { sid: 1 },
+        msg: Bytes::from(vec![b'0' + i / 3]),
+        inbox: None,
+        headers: None,
+        received_at: SystemTime::now(),
+    });
+    executor.run(events);
+    executor.shutdown().unwrap();
+    let processed = processed.lock().unwrap();
+    assert_eq!(processed.len(), 30);
+    for entity in 0..3 {
+        let subject = format!("entity.{}", entity);
+        let order: Vec<u8> = processed
+            .iter()
+            .filter(|(s, _)| *s == subject)
+            .map(|(_, i)| *i)
+            .collect();
+        assert_eq!(order, (b'0'..b'0' + 10).collect::<Vec<u8>>());
+    }
+}
+
+#[test]
+fn worker_pool_test() {
+    use crate::client::Channel;
+    use bytes::Bytes;
+    use std::time::{Duration, SystemTime};
+
+    let pool = WorkerPool::new(4);
+    let processed = Arc::new(Mutex::new(Vec::new()));
+    let dispatchers: Vec<Dispatcher> = (0..3)
+        .map(|_| {
+            let processed = processed.clone();
+            pool.dispatcher(1, move |event: Event| {
+                thread::sleep(Duration::from_millis(1));
+                processed
+                    .lock()
+                    .unwrap()
+                    .push((event.channel.sid, event.msg[0]));
+            })
+        })
+        .collect();
+    for i in 0..30u8 {
+        let sid = u64::from(i % 3);
+        let event = Event {
+            subject: "subject".to_owned(),
+            channel: Channel { sid },
+            msg: Bytes::from(vec![i / 3]),
+            inbox: None,
+            headers: None,
+            received_at: SystemTime::now(),
+        };
+        dispatchers[sid as usize].try_send(event).unwrap();
+    }
+    pool.shutdown().unwrap();
+    let processed = processed.lock().unwrap();
+    assert_eq!(processed.len(), 30);
+    for sid in 0..3 {
+        let order: Vec<u8> = processed
+            .iter()
+            .filter(|(s, _)| *s == sid)
+            .map(|(_, i)| *i)
+            .collect();
+        assert_eq!(order, (0..10).collect::<Vec<u8>>());
+    }
+}
+
+#[test]
+fn worker_pool_panic_test() {
+    use crate::client::Channel;
+    use bytes::Bytes;
+    use std::time::SystemTime;
+
+    let pool = WorkerPool::new(1);
+    let processed = Arc::new(Mutex::new(Vec::new()));
+    let processed_ = processed.clone();
+    let dispatcher = pool.dispatcher(1, move |event: Event| {
+        if event.msg[0] == 1 {
+            panic!("handler failed");
+        }
+        processed_.lock().unwrap().push(event.msg[0]);
+    });
+    for i in 0..3u8 {
+        let event = Event {
+            subject: "subject".to_owned(),
+            channel: Channel { sid: 1 },
+            msg: Bytes::from(vec![i]),
+            inbox: None,
+            headers: None,
+            received_at: SystemTime::now(),
+        };
+        dispatcher.try_send(event).unwrap();
+    }
+    let err = pool.shutdown().unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::HandlerPanicked);
+    assert_eq!(*processed.lock().unwrap(), vec![0, 2]);
+}
+
+#[test]
+fn ordered_executor_panic_test() {
+    use crate::client::Channel;
+    use bytes::Bytes;
+    use std::time::SystemTime;
+
+    let processed = Arc::new(Mutex::new(Vec::new()));
+    let processed_ = processed.clone();
+    let executor = OrderedExecutor::new(
+        2,
+        |_: &Event| 0,
+        move |event: Event| {
+            if event.msg[0] == 1 {
+                panic!("handler failed");
+            }
+            processed_.lock().unwrap().push(event.msg[0]);
+        },
+    );
+    executor.run((0..4u8).map(|i| Event {
+        subject: "entity".to_owned(),
+        channel: Channel { sid: 1 },
+        msg: Bytes::from(vec![i]),
+        inbox: None,
+        headers: None,
+        received_at: SystemTime::now(),
+    }));
+    let err = executor.shutdown().unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::HandlerPanicked);
+    // The events queued after the one that panicked were still processed
+    assert_eq!(*processed.lock().unwrap(), vec![0, 2, 3]);
+}
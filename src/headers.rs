@@ -0,0 +1,156 @@
+use crate::errors::*;
+
+const VERSION_LINE: &str = "NATS/1.0";
+
+// Message headers, sent with HPUB and received with HMSG
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Headers {
+    pub status: Option<u16>,
+    pub description: Option<String>,
+    entries: Vec<(String, String)>,
+}
+
+impl Headers {
+    pub fn new() -> Headers {
+        Headers::default()
+    }
+
+    pub fn insert(&mut self, key: &str, value: &str) -> &mut Self {
+        self.entries.push((key.to_owned(), value.to_owned()));
+        self
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> &mut Self {
+        self.remove(key);
+        self.insert(key, value)
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        self.entries.retain(|(k, _)| !k.eq_ignore_ascii_case(key));
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn get_all<'t>(&'t self, key: &'t str) -> impl Iterator<Item = &'t str> {
+        self.entries
+            .iter()
+            .filter(move |(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty() && self.status.is_none()
+    }
+
+    pub fn check(&self) -> Result<(), NatsError> {
+        for (key, value) in &self.entries {
+            if key.is_empty() || key.bytes().any(|c| c == b':' || c <= b' ' || c == 0x7f) {
+                return Err(NatsError::from((
+                    ErrorKind::ClientProtocolError,
+                    "Invalid header name",
+                    key.clone(),
+                )));
+            }
+            if value.bytes().any(|c| c == b'\r' || c == b'\n') {
+                return Err(NatsError::from((
+                    ErrorKind::ClientProtocolError,
+                    "Header values cannot contain CR or LF",
+                    key.clone(),
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = String::from(VERSION_LINE);
+        if let Some(status) = self.status {
+            out.push_str(&format!(" {}", status));
+            if let Some(ref description) = self.description {
+                out.push(' ');
+                out.push_str(description);
+            }
+        }
+        out.push_str("\r\n");
+        for (key, value) in &self.entries {
+            out.push_str(&format!("{}: {}\r\n", key, value));
+        }
+        out.push_str("\r\n");
+        out.into_bytes()
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Headers, NatsError> {
+        let invalid = || {
+            NatsError::from((
+                ErrorKind::ServerProtocolError,
+                "Invalid message headers",
+                String::from_utf8_lossy(buf).into_owned(),
+            ))
+        };
+        let text = std::str::from_utf8(buf)?;
+        let mut lines = text.split("\r\n");
+        let version_line = lines.next().ok_or_else(invalid)?;
+        if !version_line.starts_with(VERSION_LINE) {
+            return Err(invalid());
+        }
+        let mut headers = Headers::new();
+        let status_line = version_line[VERSION_LINE.len()..].trim();
+        if !status_line.is_empty() {
+            let mut parts = status_line.splitn(2, ' ');
+            headers.status = Some(
+                parts
+                    .next()
+                    .and_then(|status| status.parse().ok())
+                    .ok_or_else(invalid)?,
+            );
+            headers.description = parts.next().map(|d| d.trim().to_owned());
+        }
+        for line in lines {
+            if line.is_empty() {
+                continue;
+            }
+            let mut parts = line.splitn(2, ':');
+            let key = parts.next().ok_or_else(invalid)?.trim();
+            let value = parts.next().ok_or_else(invalid)?.trim();
+            if key.is_empty() {
+                return Err(invalid());
+            }
+            headers.insert(key, value);
+        }
+        Ok(headers)
+    }
+}
+
+#[test]
+fn headers_test() {
+    let mut headers = Headers::new();
+    headers.insert("Trace-Id", "abc").insert("Trace-Id", "def");
+    headers.set("Content-Type", "application/json");
+    let encoded = headers.encode();
+    assert_eq!(
+        encoded,
+        b"NATS/1.0\r\nTrace-Id: abc\r\nTrace-Id: def\r\nContent-Type: application/json\r\n\r\n"
+    );
+    let decoded = Headers::decode(&encoded).unwrap();
+    assert_eq!(decoded, headers);
+    assert_eq!(decoded.get("trace-id"), Some("abc"));
+    assert_eq!(decoded.get_all("Trace-Id").count(), 2);
+
+    let decoded = Headers::decode(b"NATS/1.0 503 No Responders\r\n\r\n").unwrap();
+    assert_eq!(decoded.status, Some(503));
+    assert_eq!(decoded.description.as_deref(), Some("No Responders"));
+    assert!(Headers::decode(b"HTTP/1.1 200\r\n\r\n").is_err());
+}
@@ -0,0 +1,58 @@
+use crate::client::Event;
+use crate::errors::*;
+use crate::headers::Headers;
+
+pub const PAYLOAD_CHECKSUM_HEADER: &str = "Nats-Payload-Crc32";
+
+// What to do with a received message whose payload doesn't match its checksum
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ChecksumMismatchPolicy {
+    Error,
+    Drop,
+}
+
+// CRC-32 (IEEE 802.3)
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &c in data {
+        crc ^= u32::from(c);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (!(crc & 1)).wrapping_add(1));
+        }
+    }
+    !crc
+}
+
+pub(crate) fn add_checksum(headers: &mut Headers, msg: &[u8]) {
+    headers.set(PAYLOAD_CHECKSUM_HEADER, &format!("{:08x}", crc32(msg)));
+}
+
+// Messages without a checksum header are accepted as-is
+pub(crate) fn verify_checksum(event: &Event) -> Result<(), NatsError> {
+    let expected = match event
+        .headers
+        .as_ref()
+        .and_then(|headers| headers.get(PAYLOAD_CHECKSUM_HEADER))
+    {
+        None => return Ok(()),
+        Some(expected) => expected,
+    };
+    let computed = format!("{:08x}", crc32(&event.msg));
+    if !expected.eq_ignore_ascii_case(&computed) {
+        return Err(NatsError::from((
+            ErrorKind::IntegrityError,
+            "Payload checksum mismatch",
+            format!(
+                "{}: expected {}, computed {}",
+                event.subject, expected, computed
+            ),
+        )));
+    }
+    Ok(())
+}
+
+#[test]
+fn crc32_test() {
+    assert_eq!(crc32(b""), 0);
+    assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+}
@@ -0,0 +1,224 @@
+use crate::client::Event;
+use crate::errors::*;
+use crate::headers::Headers;
+use std::{borrow::Cow, fmt};
+
+// Message about to be published, as seen by interceptors
+#[derive(Clone, Debug)]
+pub struct OutgoingMessage<'m> {
+    pub subject: &'m str,
+    pub headers: Headers,
+    pub msg: Cow<'m, [u8]>,
+}
+
+// Observes and modifies messages going through the client. Interceptors are
+// called in the order they were added, before payloads are compressed and
+// encrypted, and after received payloads are decrypted and decompressed.
+// Payloads of encrypted subjects are never compressed.
+pub trait Interceptor: Send {
+    // Called before a message is published. An error cancels the publication
+    // and is returned to the publisher.
+    fn on_publish(&mut self, _message: &mut OutgoingMessage<'_>) -> Result<(), NatsError> {
+        Ok(())
+    }
+
+    // Called before a received event is returned. The event is dropped if
+    // `false` is returned, and the error is returned instead of the event.
+    fn on_event(&mut self, _event: &mut Event) -> Result<bool, NatsError> {
+        Ok(true)
+    }
+}
+
+impl fmt::Debug for dyn Interceptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Interceptor {{}}")
+    }
+}
+
+#[test]
+fn interceptor_test() {
+    use crate::client::Client;
+    use crate::crypto::PayloadCipher;
+    use crate::encryption::PAYLOAD_ENCRYPTION_HEADER;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::thread;
+
+    // Sends every published message back to the subscriber, and reports
+    // what was sent over the wire
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (frames, wire) = mpsc::channel();
+    let server = thread::spawn(move || {
+        let (mut tcp, _) = listener.accept().unwrap();
+        tcp.write_all(b"INFO {\"max_payload\":1048576,\"headers\":true}\r\n")
+            .unwrap();
+        let mut reader = BufReader::new(tcp.try_clone().unwrap());
+        let mut line = String::new();
+        let mut sid = String::new();
+        while reader.read_line(&mut line).unwrap_or(0) > 0 {
+            let cmd = line.trim_end().to_owned();
+            line.clear();
+            let args: Vec<&str> = cmd.split(' ').collect();
+            match args[0] {
+                "PING" => tcp.write_all(b"PONG\r\n").unwrap(),
+                "SUB" => sid = args[2].to_owned(),
+                "PUB" | "HPUB" => {
+                    let len: usize = args[args.len() - 1].parse().unwrap();
+                    let mut payload = vec![0; len + 2];
+                    reader.read_exact(&mut payload).unwrap();
+                    let mut frame = match args[0] {
+                        "PUB" => format!("MSG {} {} {}\r\n", args[1], sid, len),
+                        _ => format!("HMSG {} {} {} {}\r\n", args[1], sid, args[2], len),
+                    }
+                    .into_bytes();
+                    frame.extend_from_slice(&payload);
+                    tcp.write_all(&frame).unwrap();
+                    frames.send(payload).unwrap();
+                }
+                _ => {}
+            }
+        }
+    });
+
+    #[derive(Default)]
+    struct Recorder {
+        published: Arc<Mutex<Vec<Vec<u8>>>>,
+        received: Arc<Mutex<Vec<Vec<u8>>>>,
+    }
+
+    impl Interceptor for Recorder {
+        fn on_publish(&mut self, message: &mut OutgoingMessage<'_>) -> Result<(), NatsError> {
+            self.published.lock().unwrap().push(message.msg.to_vec());
+            message.headers.set("Trace-Id", "42");
+            message.msg = message.msg.to_ascii_uppercase().into();
+            Ok(())
+        }
+
+        fn on_event(&mut self, event: &mut Event) -> Result<bool, NatsError> {
+            self.received.lock().unwrap().push(event.msg.to_vec());
+            match event.subject.as_str() {
+                "drop" => Ok(false),
+                "fail" => Err(NatsError::from((ErrorKind::DecodeError, "Rejected"))),
+                _ => Ok(true),
+            }
+        }
+    }
+
+    let recorder = Recorder::default();
+    let published = recorder.published.clone();
+    let received = recorder.received.clone();
+    let cipher = Arc::new(PayloadCipher::new("k", [7; 32]).unwrap());
+    let mut client = Client::new(format!("nats://127.0.0.1:{}", port).as_str()).unwrap();
+    client.add_interceptor(recorder);
+    client.set_payload_encryption(move |subject: &str| {
+        if subject == "secret" {
+            Some(cipher.clone())
+        } else {
+            None
+        }
+    });
+    let channel = client.subscribe(">", None).unwrap();
+
+    // Headers and payloads set by interceptors are sent
+    client.publish("a", b"hello").unwrap();
+    assert!(String::from_utf8_lossy(&wire.recv().unwrap()).contains("Trace-Id: 42\r\n"));
+    let event = client.wait().unwrap();
+    assert_eq!(event.msg.as_ref(), b"HELLO");
+    assert_eq!(event.headers.unwrap().get("Trace-Id"), Some("42"));
+
+    // Interceptors see the payloads in clear, before encryption and after
+    // decryption
+    client.publish("secret", b"hello").unwrap();
+    let frame = String::from_utf8_lossy(&wire.recv().unwrap()).into_owned();
+    assert!(frame.contains(PAYLOAD_ENCRYPTION_HEADER) && !frame.contains("HELLO"));
+    assert_eq!(client.wait().unwrap().msg.as_ref(), b"HELLO");
+    assert_eq!(*published.lock().unwrap(), [b"hello", b"hello"]);
+    assert_eq!(*received.lock().unwrap(), [b"HELLO", b"HELLO"]);
+
+    // Dropped events are skipped, and errors returned instead of the event
+    client.publish("drop", b"x").unwrap();
+    client.publish("fail", b"x").unwrap();
+    client.publish("a", b"x").unwrap();
+    let e = client.wait().unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::DecodeError);
+    assert_eq!(client.wait().unwrap().subject, "a");
+    assert_eq!(channel.stats(&client).unwrap().dropped, 1);
+    client.close().unwrap();
+    server.join().unwrap();
+}
+
+#[cfg(feature = "gzip")]
+#[test]
+fn interceptor_compression_test() {
+    use crate::client::Client;
+    use crate::compression::{CompressionAlgorithm, PayloadCompression, PAYLOAD_ENCODING_HEADER};
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = thread::spawn(move || {
+        let (mut tcp, _) = listener.accept().unwrap();
+        tcp.write_all(b"INFO {\"max_payload\":1048576,\"headers\":true}\r\n")
+            .unwrap();
+        let mut reader = BufReader::new(tcp.try_clone().unwrap());
+        let mut line = String::new();
+        let mut sid = String::new();
+        while reader.read_line(&mut line).unwrap_or(0) > 0 {
+            let cmd = line.trim_end().to_owned();
+            line.clear();
+            let args: Vec<&str> = cmd.split(' ').collect();
+            match args[0] {
+                "PING" => tcp.write_all(b"PONG\r\n").unwrap(),
+                "SUB" => sid = args[2].to_owned(),
+                "HPUB" => {
+                    let len: usize = args[3].parse().unwrap();
+                    let mut payload = vec![0; len + 2];
+                    reader.read_exact(&mut payload).unwrap();
+                    let mut frame =
+                        format!("HMSG {} {} {} {}\r\n", args[1], sid, args[2], len).into_bytes();
+                    frame.extend_from_slice(&payload);
+                    tcp.write_all(&frame).unwrap();
+                }
+                _ => {}
+            }
+        }
+    });
+
+    struct Recorder(Arc<Mutex<Vec<Vec<u8>>>>);
+
+    impl Interceptor for Recorder {
+        fn on_publish(&mut self, message: &mut OutgoingMessage<'_>) -> Result<(), NatsError> {
+            self.0.lock().unwrap().push(message.msg.to_vec());
+            Ok(())
+        }
+
+        fn on_event(&mut self, event: &mut Event) -> Result<bool, NatsError> {
+            assert!(event
+                .headers
+                .as_ref()
+                .and_then(|headers| headers.get(PAYLOAD_ENCODING_HEADER))
+                .is_none());
+            self.0.lock().unwrap().push(event.msg.to_vec());
+            Ok(true)
+        }
+    }
+
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let mut client = Client::new(format!("nats://127.0.0.1:{}", port).as_str()).unwrap();
+    client.add_interceptor(Recorder(seen.clone()));
+    let mut compression = PayloadCompression::new(CompressionAlgorithm::Gzip);
+    compression.threshold = 0;
+    client.set_payload_compression(Some(compression)).unwrap();
+    client.subscribe("a", None).unwrap();
+    let payload = b"{\"value\": 42}".repeat(200);
+    client.publish("a", &payload).unwrap();
+    assert_eq!(client.wait().unwrap().msg.as_ref(), payload.as_slice());
+    assert_eq!(*seen.lock().unwrap(), [payload.clone(), payload]);
+    client.close().unwrap();
+    server.join().unwrap();
+}
@@ -0,0 +1,561 @@
+use crate::client::{Channel, Client, Event};
+use crate::errors::*;
+use crate::reply::StatusCode;
+use serde_json::{json, Value};
+use std::{
+    cmp,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+const ACK_PREFIX: &str = "$JS.ACK.";
+const ACK_TOKENS_V1: usize = 7;
+const ACK_TOKENS_V2: usize = 9;
+const ACK: &str = "+ACK";
+const NAK: &str = "-NAK";
+const IN_PROGRESS: &str = "+WPI";
+const TERM: &str = "+TERM";
+const LAST_CONSUMER_HEADER: &str = "Nats-Last-Consumer";
+const ORDERED_HEARTBEAT_MS: u64 = 5000;
+// Heartbeats missed before an ordered consumer is recreated
+const ORDERED_MISSED_HEARTBEATS: u32 = 3;
+
+// Metadata encoded by JetStream in the reply subject of consumer deliveries
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct JsMessageInfo {
+    pub domain: Option<String>,
+    pub account_hash: Option<String>,
+    pub stream: String,
+    pub consumer: String,
+    pub delivered: u64,
+    pub stream_seq: u64,
+    pub consumer_seq: u64,
+    pub timestamp: SystemTime,
+    pub pending: u64,
+}
+
+impl JsMessageInfo {
+    // Parses `$JS.ACK.<stream>.<consumer>.<delivered>.<sseq>.<cseq>.<ts>.<pending>`
+    // as well as the newer form prefixed with `<domain>.<account hash>`
+    pub fn parse(reply: &str) -> Option<JsMessageInfo> {
+        if !reply.starts_with(ACK_PREFIX) {
+            return None;
+        }
+        let tokens: Vec<&str> = reply[ACK_PREFIX.len()..].split('.').collect();
+        let (domain, account_hash, tokens) = match tokens.len() {
+            ACK_TOKENS_V1 => (None, None, &tokens[..]),
+            len if len >= ACK_TOKENS_V2 => {
+                let domain = match tokens[0] {
+                    "_" | "" => None,
+                    domain => Some(domain.to_owned()),
+                };
+                (
+                    domain,
+                    Some(tokens[1].to_owned()),
+                    &tokens[2..ACK_TOKENS_V2],
+                )
+            }
+            _ => return None,
+        };
+        if tokens[0].is_empty() || tokens[1].is_empty() {
+            return None;
+        }
+        let nanos: u64 = tokens[5].parse().ok()?;
+        Some(JsMessageInfo {
+            domain,
+            account_hash,
+            stream: tokens[0].to_owned(),
+            consumer: tokens[1].to_owned(),
+            delivered: tokens[2].parse().ok()?,
+            stream_seq: tokens[3].parse().ok()?,
+            consumer_seq: tokens[4].parse().ok()?,
+            timestamp: UNIX_EPOCH + Duration::from_nanos(nanos),
+            pending: tokens[6].parse().ok()?,
+        })
+    }
+}
+
+impl Event {
+    pub fn jetstream_meta(&self) -> Option<JsMessageInfo> {
+        self.inbox
+            .as_ref()
+            .and_then(|inbox| JsMessageInfo::parse(inbox))
+    }
+
+    // Returns a handle to acknowledge the message, if it was delivered by a
+    // JetStream consumer
+    pub fn ack_handle(&self) -> Option<AckHandle> {
+        let inbox = self.inbox.as_ref()?;
+        let info = JsMessageInfo::parse(inbox)?;
+        Some(AckHandle {
+            subject: inbox.clone(),
+            info,
+            acked: false,
+        })
+    }
+}
+
+// Acknowledgment of a message delivered by a JetStream consumer. A message can
+// only be acknowledged once, but `in_progress()` can be sent any number of
+// times before, to reset the redelivery timer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AckHandle {
+    subject: String,
+    info: JsMessageInfo,
+    acked: bool,
+}
+
+impl AckHandle {
+    pub fn subject(&self) -> &str {
+        &self.subject
+    }
+
+    pub fn info(&self) -> &JsMessageInfo {
+        &self.info
+    }
+
+    // Number of times the message was delivered, including this one. Can be
+    // used to `term()` messages that keep failing.
+    pub fn num_delivered(&self) -> u64 {
+        self.info.delivered
+    }
+
+    pub fn stream_sequence(&self) -> u64 {
+        self.info.stream_seq
+    }
+
+    pub fn consumer_sequence(&self) -> u64 {
+        self.info.consumer_seq
+    }
+
+    // When the message was stored in the stream
+    pub fn timestamp(&self) -> SystemTime {
+        self.info.timestamp
+    }
+
+    pub fn is_acked(&self) -> bool {
+        self.acked
+    }
+
+    // The message was processed
+    pub fn ack(&mut self, client: &mut Client) -> Result<(), NatsError> {
+        self.send(client, ACK)
+    }
+
+    // Same as `ack()`, but waits for the server to confirm that the
+    // acknowledgment was received, so that the message will not be
+    // redelivered
+    pub fn double_ack(&mut self, client: &mut Client, timeout: Duration) -> Result<(), NatsError> {
+        self.check_not_acked()?;
+        client.request(&self.subject, ACK.as_bytes(), timeout)?;
+        self.acked = true;
+        Ok(())
+    }
+
+    // The message was not processed, and should be redelivered, after
+    // `delay` if set, or immediately otherwise
+    pub fn nak(&mut self, client: &mut Client, delay: Option<Duration>) -> Result<(), NatsError> {
+        self.send(client, &nak_payload(delay))
+    }
+
+    // The message is still being processed. Doesn't acknowledge it.
+    pub fn in_progress(&mut self, client: &mut Client) -> Result<(), NatsError> {
+        self.check_not_acked()?;
+        client.publish(&self.subject, IN_PROGRESS.as_bytes())
+    }
+
+    // The message cannot be processed, and should never be redelivered
+    pub fn term(&mut self, client: &mut Client) -> Result<(), NatsError> {
+        self.send(client, TERM)
+    }
+
+    fn send(&mut self, client: &mut Client, payload: &str) -> Result<(), NatsError> {
+        self.check_not_acked()?;
+        client.publish(&self.subject, payload.as_bytes())?;
+        self.acked = true;
+        Ok(())
+    }
+
+    fn check_not_acked(&self) -> Result<(), NatsError> {
+        if self.acked {
+            return Err(NatsError::from((
+                ErrorKind::ClientProtocolError,
+                "The message was already acknowledged",
+                self.subject.clone(),
+            )));
+        }
+        Ok(())
+    }
+}
+
+fn nak_payload(delay: Option<Duration>) -> String {
+    match delay {
+        None => NAK.to_owned(),
+        Some(delay) => format!("{} {}", NAK, json!({ "delay": delay.as_nanos() as u64 })),
+    }
+}
+
+// Ephemeral consumer delivering the messages of a stream in order, without
+// acknowledgments. It is recreated from the last message received if a
+// message is missed, or if the server stops sending heartbeats.
+#[derive(Debug)]
+pub(crate) struct OrderedConsumer {
+    stream: String,
+    filter: String,
+    // Only deliver the last message of each subject, then the new ones
+    last_per_subject: bool,
+    // Timeout of the JetStream API requests
+    timeout: Duration,
+    // The consumer is recreated when nothing, not even a heartbeat, was
+    // received for that long
+    idle_timeout: Duration,
+    channel: Option<Channel>,
+    consumer: Option<String>,
+    stream_seq: u64,
+    consumer_seq: u64,
+    caught_up: bool,
+}
+
+impl OrderedConsumer {
+    pub(crate) fn new(
+        stream: &str,
+        filter: &str,
+        last_per_subject: bool,
+        timeout: Duration,
+    ) -> OrderedConsumer {
+        OrderedConsumer {
+            stream: stream.to_owned(),
+            filter: filter.to_owned(),
+            last_per_subject,
+            timeout,
+            idle_timeout: Duration::from_millis(ORDERED_HEARTBEAT_MS) * ORDERED_MISSED_HEARTBEATS,
+            channel: None,
+            consumer: None,
+            stream_seq: 0,
+            consumer_seq: 0,
+            caught_up: false,
+        }
+    }
+
+    // Whether the messages stored when the consumer was created have all
+    // been delivered
+    pub(crate) fn is_caught_up(&self) -> bool {
+        self.caught_up
+    }
+
+    // Returns the next message and its metadata, or `None` if nothing was
+    // delivered before the timeout
+    pub(crate) fn next_timeout(
+        &mut self,
+        client: &mut Client,
+        timeout: Duration,
+    ) -> Result<Option<(Event, JsMessageInfo)>, NatsError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            let wait = cmp::min(deadline - now, self.idle_timeout);
+            let channel = match self.channel {
+                Some(channel) => channel,
+                None => {
+                    self.create(client)?;
+                    continue;
+                }
+            };
+            match client.select(&[channel], wait)? {
+                Some(event) => {
+                    if let Some(delivery) = self.handle_event(client, event)? {
+                        return Ok(Some(delivery));
+                    }
+                }
+                None if wait == self.idle_timeout => self.reset(client),
+                None => {}
+            }
+        }
+    }
+
+    fn handle_event(
+        &mut self,
+        client: &mut Client,
+        event: Event,
+    ) -> Result<Option<(Event, JsMessageInfo)>, NatsError> {
+        let status = event.headers.as_ref().and_then(|headers| headers.status);
+        if status == Some(StatusCode::IdleHeartbeat.code()) {
+            // Flow control requests carry a subject to reply to
+            if let Some(ref inbox) = event.inbox {
+                client.publish(inbox, b"")?;
+            }
+            let last_consumer_seq = event
+                .headers
+                .as_ref()
+                .and_then(|headers| headers.get(LAST_CONSUMER_HEADER))
+                .and_then(|seq| seq.parse::<u64>().ok());
+            if last_consumer_seq.is_some_and(|seq| seq != self.consumer_seq) {
+                self.reset(client);
+            }
+            return Ok(None);
+        }
+        let meta = match event.jetstream_meta() {
+            None => return Ok(None),
+            Some(meta) => meta,
+        };
+        if meta.consumer_seq != self.consumer_seq + 1 {
+            self.reset(client);
+            return Ok(None);
+        }
+        self.consumer_seq = meta.consumer_seq;
+        self.stream_seq = meta.stream_seq;
+        if meta.pending == 0 {
+            self.caught_up = true;
+        }
+        Ok(Some((event, meta)))
+    }
+
+    pub(crate) fn create(&mut self, client: &mut Client) -> Result<(), NatsError> {
+        let res = self.try_create(client);
+        if res.is_err() {
+            self.reset(client);
+        }
+        res
+    }
+
+    fn try_create(&mut self, client: &mut Client) -> Result<(), NatsError> {
+        let inbox = client.new_inbox();
+        let channel = client.subscribe(&inbox, None)?;
+        self.channel = Some(channel);
+        let deliver_policy = if self.last_per_subject {
+            "last_per_subject"
+        } else {
+            "all"
+        };
+        let mut config = json!({
+            "deliver_subject": inbox,
+            "deliver_policy": deliver_policy,
+            "ack_policy": "none",
+            "max_deliver": 1,
+            "filter_subject": self.filter,
+            "flow_control": true,
+            "idle_heartbeat": Duration::from_millis(ORDERED_HEARTBEAT_MS).as_nanos() as u64,
+            "mem_storage": true,
+            "num_replicas": 1,
+            "replay_policy": "instant",
+        });
+        // Resumes after the last message received
+        if self.stream_seq > 0 {
+            config["deliver_policy"] = json!("by_start_sequence");
+            config["opt_start_seq"] = json!(self.stream_seq + 1);
+        }
+        let request = json!({ "stream_name": self.stream, "config": config });
+        let api = format!("$JS.API.CONSUMER.CREATE.{}", self.stream);
+        let reply = client.request(&api, request.to_string().as_bytes(), self.timeout)?;
+        let info: Value = reply.decode_json()?;
+        if let Some(error) = info.get("error") {
+            return Err(NatsError::from((
+                ErrorKind::ServerProtocolError,
+                "Unable to create an ordered consumer",
+                error.to_string(),
+            )));
+        }
+        self.consumer = info
+            .get("name")
+            .and_then(|name| name.as_str())
+            .map(|name| name.to_owned());
+        self.consumer_seq = 0;
+        if info.get("num_pending").and_then(|n| n.as_u64()) == Some(0) {
+            self.caught_up = true;
+        }
+        Ok(())
+    }
+
+    // Removes the current consumer. A new one is created by the next read.
+    pub(crate) fn reset(&mut self, client: &mut Client) {
+        if let Some(channel) = self.channel.take() {
+            let _ = client.unsubscribe(channel);
+        }
+        if let Some(consumer) = self.consumer.take() {
+            let api = format!("$JS.API.CONSUMER.DELETE.{}.{}", self.stream, consumer);
+            let _ = client.publish(&api, b"");
+        }
+    }
+}
+
+#[test]
+fn jetstream_meta_test() {
+    let info = JsMessageInfo::parse("$JS.ACK.ORDERS.worker.2.41.7.1600000000000000000.5").unwrap();
+    assert_eq!(info.domain, None);
+    assert_eq!(info.stream, "ORDERS");
+    assert_eq!(info.consumer, "worker");
+    assert_eq!(info.delivered, 2);
+    assert_eq!(info.stream_seq, 41);
+    assert_eq!(info.consumer_seq, 7);
+    assert_eq!(
+        info.timestamp,
+        UNIX_EPOCH + Duration::from_secs(1_600_000_000)
+    );
+    assert_eq!(info.pending, 5);
+
+    let info =
+        JsMessageInfo::parse("$JS.ACK.hub.ACCHASH.ORDERS.worker.1.3.3.1600000000000000000.0.rnd")
+            .unwrap();
+    assert_eq!(info.domain, Some("hub".to_owned()));
+    assert_eq!(info.account_hash, Some("ACCHASH".to_owned()));
+    assert_eq!(info.stream_seq, 3);
+
+    assert!(JsMessageInfo::parse("_INBOX.abc").is_none());
+    assert!(JsMessageInfo::parse("$JS.ACK.ORDERS.worker.x.1.1.1.0").is_none());
+}
+
+#[test]
+fn ack_handle_test() {
+    use bytes::Bytes;
+
+    let event = Event {
+        subject: "orders.new".to_owned(),
+        channel: Channel { sid: 1 },
+        msg: Bytes::new(),
+        inbox: Some("$JS.ACK.ORDERS.worker.4.12.3.1600000000000000000.0".to_owned()),
+        headers: None,
+        received_at: SystemTime::now(),
+    };
+    let handle = event.ack_handle().unwrap();
+    assert_eq!(handle.subject(), event.inbox.as_deref().unwrap());
+    assert!(!handle.is_acked());
+    assert_eq!(handle.num_delivered(), 4);
+    assert_eq!(handle.stream_sequence(), 12);
+    assert_eq!(handle.consumer_sequence(), 3);
+    assert_eq!(
+        handle.timestamp(),
+        UNIX_EPOCH + Duration::from_secs(1_600_000_000)
+    );
+    assert!(Event {
+        inbox: Some("_INBOX.x".to_owned()),
+        ..event
+    }
+    .ack_handle()
+    .is_none());
+
+    assert_eq!(nak_payload(None), "-NAK");
+    assert_eq!(
+        nak_payload(Some(Duration::from_secs(2))),
+        "-NAK {\"delay\":2000000000}"
+    );
+}
+
+#[test]
+fn ordered_consumer_test() {
+    use crate::headers::Headers;
+    use bytes::Bytes;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (requests, received) = mpsc::channel();
+    let server = thread::spawn(move || {
+        let (mut tcp, _) = listener.accept().unwrap();
+        tcp.write_all(b"INFO {\"max_payload\":1048576,\"headers\":true}\r\n")
+            .unwrap();
+        let mut reader = BufReader::new(tcp.try_clone().unwrap());
+        let mut line = String::new();
+        let mut subs: Vec<(String, String)> = Vec::new();
+        while reader.read_line(&mut line).unwrap_or(0) > 0 {
+            let cmd = line.trim_end().to_owned();
+            line.clear();
+            let args: Vec<&str> = cmd.split(' ').collect();
+            match args[0] {
+                "PING" => tcp.write_all(b"PONG\r\n").unwrap(),
+                "SUB" => subs.push((args[1].to_owned(), args[2].to_owned())),
+                "PUB" => {
+                    reader.read_line(&mut line).unwrap();
+                    let payload = line.trim_end().to_owned();
+                    line.clear();
+                    if args[1].starts_with("$JS.API.CONSUMER.DELETE.") {
+                        requests.send("DELETE".to_owned()).unwrap();
+                    }
+                    if args[1] != "$JS.API.CONSUMER.CREATE.S" {
+                        continue;
+                    }
+                    requests.send(payload).unwrap();
+                    let sid = &subs
+                        .iter()
+                        .find(|(subject, _)| args[2].starts_with(subject.trim_end_matches('*')))
+                        .unwrap()
+                        .1;
+                    let info = "{\"name\":\"c1\",\"num_pending\":5}";
+                    let reply = format!("MSG {} {} {}\r\n{}\r\n", args[2], sid, info.len(), info);
+                    tcp.write_all(reply.as_bytes()).unwrap();
+                }
+                _ => {}
+            }
+        }
+    });
+
+    let mut client = Client::new(format!("nats://127.0.0.1:{}", port).as_str()).unwrap();
+    let mut consumer = OrderedConsumer::new("S", "orders.>", false, Duration::from_secs(5));
+    consumer.create(&mut client).unwrap();
+    let config: Value = serde_json::from_str(&received.recv().unwrap()).unwrap();
+    assert_eq!(config["config"]["deliver_policy"], "all");
+    let message = |consumer: &OrderedConsumer, consumer_seq: u64| Event {
+        subject: "orders.new".to_owned(),
+        channel: consumer.channel.unwrap(),
+        msg: Bytes::new(),
+        inbox: Some(format!(
+            "$JS.ACK.S.c1.1.{}.{}.1600000000000000000.3",
+            consumer_seq + 9,
+            consumer_seq
+        )),
+        headers: None,
+        received_at: SystemTime::now(),
+    };
+    let heartbeat = |consumer: &OrderedConsumer, last_consumer_seq: u64| {
+        let mut headers = Headers::new();
+        headers.status = Some(StatusCode::IdleHeartbeat.code());
+        headers.set(LAST_CONSUMER_HEADER, &last_consumer_seq.to_string());
+        Event {
+            subject: "_INBOX.hb".to_owned(),
+            channel: consumer.channel.unwrap(),
+            msg: Bytes::new(),
+            inbox: None,
+            headers: Some(headers),
+            received_at: SystemTime::now(),
+        }
+    };
+
+    let event = message(&consumer, 1);
+    let (_, meta) = consumer.handle_event(&mut client, event).unwrap().unwrap();
+    assert_eq!(meta.stream_seq, 10);
+    let event = heartbeat(&consumer, 1);
+    assert!(consumer.handle_event(&mut client, event).unwrap().is_none());
+    assert!(consumer.channel.is_some());
+
+    // A gap in the consumer sequence recreates the consumer after the last
+    // message received
+    let event = message(&consumer, 3);
+    assert!(consumer.handle_event(&mut client, event).unwrap().is_none());
+    assert!(consumer.channel.is_none());
+    assert_eq!(received.recv().unwrap(), "DELETE");
+    consumer.create(&mut client).unwrap();
+    let config: Value = serde_json::from_str(&received.recv().unwrap()).unwrap();
+    assert_eq!(config["config"]["deliver_policy"], "by_start_sequence");
+    assert_eq!(config["config"]["opt_start_seq"], 11);
+
+    // So does a heartbeat reporting messages that weren't received
+    let event = heartbeat(&consumer, 2);
+    assert!(consumer.handle_event(&mut client, event).unwrap().is_none());
+    assert!(consumer.channel.is_none());
+    assert_eq!(received.recv().unwrap(), "DELETE");
+
+    // And missing heartbeats
+    consumer.idle_timeout = Duration::from_millis(100);
+    assert!(consumer
+        .next_timeout(&mut client, Duration::from_millis(150))
+        .unwrap()
+        .is_none());
+    assert!(received.recv().unwrap().contains("by_start_sequence"));
+    assert_eq!(received.recv().unwrap(), "DELETE");
+    client.close().unwrap();
+    server.join().unwrap();
+}
@@ -0,0 +1,105 @@
+use crate::client::{Channel, Client, Event};
+use crate::errors::*;
+use serde::{de::DeserializeOwned, Serialize};
+use std::marker::PhantomData;
+
+// Subscription whose payloads are JSON-encoded values of type `T`
+#[derive(Debug)]
+pub struct TypedChannel<T> {
+    pub channel: Channel,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> Clone for TypedChannel<T> {
+    fn clone(&self) -> TypedChannel<T> {
+        *self
+    }
+}
+
+impl<T> Copy for TypedChannel<T> {}
+
+impl<T: DeserializeOwned> TypedChannel<T> {
+    // Returns `None` if the event was delivered to a different subscription
+    pub fn decode(&self, event: &Event) -> Option<Result<T, NatsError>> {
+        if event.channel.sid != self.channel.sid {
+            return None;
+        }
+        Some(event.decode_json())
+    }
+}
+
+impl Event {
+    pub fn decode_json<T: DeserializeOwned>(&self) -> Result<T, NatsError> {
+        decode_json(&self.msg)
+    }
+}
+
+pub(crate) fn decode_json<T: DeserializeOwned>(msg: &[u8]) -> Result<T, NatsError> {
+    serde_json::from_slice(msg).map_err(|e| {
+        NatsError::from((
+            ErrorKind::DecodeError,
+            "Unable to decode the JSON payload",
+            e,
+        ))
+    })
+}
+
+impl Client {
+    pub fn publish_json<T: Serialize + ?Sized>(
+        &mut self,
+        subject: &str,
+        value: &T,
+    ) -> Result<(), NatsError> {
+        let msg = serde_json::to_vec(value).map_err(|e| {
+            NatsError::from((
+                ErrorKind::TypeError,
+                "Unable to encode the value as JSON",
+                e,
+            ))
+        })?;
+        self.publish(subject, &msg)
+    }
+
+    pub fn subscribe_json<T: DeserializeOwned>(
+        &mut self,
+        subject: &str,
+        queue: Option<&str>,
+    ) -> Result<TypedChannel<T>, NatsError> {
+        let channel = self.subscribe(subject, queue)?;
+        Ok(TypedChannel {
+            channel,
+            _marker: PhantomData,
+        })
+    }
+}
+
+#[test]
+fn decode_json_test() {
+    use bytes::Bytes;
+    use std::collections::HashMap;
+    use std::time::SystemTime;
+
+    let typed: TypedChannel<HashMap<String, u32>> = TypedChannel {
+        channel: Channel { sid: 1 },
+        _marker: PhantomData,
+    };
+    let mut event = Event {
+        subject: "counters".to_owned(),
+        channel: Channel { sid: 1 },
+        msg: Bytes::from_static(br#"{"a":1,"b":2}"#),
+        inbox: None,
+        headers: None,
+        received_at: SystemTime::now(),
+    };
+    assert_eq!(typed.decode(&event).unwrap().unwrap()["b"], 2);
+    event.msg = Bytes::from_static(b"not json");
+    let err = typed.decode(&event).unwrap().unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::DecodeError);
+    let source = std::error::Error::source(&err).unwrap();
+    assert!(source
+        .downcast_ref::<serde_json::Error>()
+        .unwrap()
+        .is_syntax());
+    event.channel = Channel { sid: 2 };
+    assert!(typed.decode(&event).is_none());
+}
@@ -0,0 +1,286 @@
+use crate::client::Client;
+use crate::crypto::PayloadCipher;
+use crate::errors::*;
+use crate::headers::Headers;
+use crate::jetstream::OrderedConsumer;
+use crate::reply::{Reply, StatusCode};
+use bytes::Bytes;
+use serde_json::{self, Value};
+use std::time::Duration;
+
+const DEFAULT_KV_TIMEOUT_MS: u64 = 5000;
+const KV_OPERATION_HEADER: &str = "KV-Operation";
+const SEQUENCE_HEADER: &str = "Nats-Sequence";
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Operation {
+    Put,
+    Delete,
+    Purge,
+}
+
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub bucket: String,
+    pub key: String,
+    pub value: Bytes,
+    pub revision: u64,
+    pub operation: Operation,
+}
+
+// Access to an existing JetStream key-value bucket
+#[derive(Debug)]
+pub struct KeyValue {
+    bucket: String,
+    timeout: Duration,
+    cipher: Option<PayloadCipher>,
+}
+
+impl KeyValue {
+    pub fn new(bucket: &str) -> Result<KeyValue, NatsError> {
+        if bucket.is_empty()
+            || !bucket
+                .bytes()
+                .all(|c| c.is_ascii_alphanumeric() || c == b'_' || c == b'-')
+        {
+            return Err(NatsError::from((
+                ErrorKind::InvalidClientConfig,
+                "Invalid bucket name",
+                bucket.to_owned(),
+            )));
+        }
+        Ok(KeyValue {
+            bucket: bucket.to_owned(),
+            timeout: Duration::from_millis(DEFAULT_KV_TIMEOUT_MS),
+            cipher: None,
+        })
+    }
+
+    pub fn bucket(&self) -> &str {
+        &self.bucket
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    // Encrypts values before `put()` and decrypts them after `get()`
+    pub fn set_cipher(&mut self, cipher: Option<PayloadCipher>) {
+        self.cipher = cipher;
+    }
+
+    pub fn cipher_mut(&mut self) -> Option<&mut PayloadCipher> {
+        self.cipher.as_mut()
+    }
+
+    // Stores a value, and returns its revision
+    pub fn put(&self, client: &mut Client, key: &str, value: &[u8]) -> Result<u64, NatsError> {
+        let subject = self.subject(key)?;
+        let reply = match self.cipher {
+            Some(ref cipher) => {
+                let sealed = cipher.seal(&subject, value)?;
+                client.request(&subject, &sealed, self.timeout)?
+            }
+            None => client.request(&subject, value, self.timeout)?,
+        };
+        let ack: Value = reply.decode_json()?;
+        if let Some(error) = ack.get("error") {
+            return Err(NatsError::from((
+                ErrorKind::ServerProtocolError,
+                "Value not stored",
+                error.to_string(),
+            )));
+        }
+        ack.get("seq").and_then(|seq| seq.as_u64()).ok_or_else(|| {
+            NatsError::from((
+                ErrorKind::ServerProtocolError,
+                "Invalid acknowledgment",
+                ack.to_string(),
+            ))
+        })
+    }
+
+    // Returns the latest value of a key, or `None` if it doesn't exist or was
+    // deleted. Requires a bucket allowing direct gets.
+    pub fn get(&self, client: &mut Client, key: &str) -> Result<Option<Entry>, NatsError> {
+        let subject = self.subject(key)?;
+        let api = format!("$JS.API.DIRECT.GET.KV_{}.{}", self.bucket, subject);
+        let reply = client.request(&api, b"", self.timeout)?;
+        if reply.status == Some(StatusCode::NotFound) {
+            return Ok(None);
+        }
+        let entry = self.entry_from_reply(key, &reply)?;
+        match entry.operation {
+            Operation::Put => Ok(Some(entry)),
+            _ => Ok(None),
+        }
+    }
+
+    // Returns the latest value of the keys matching `keys`, which can contain
+    // wildcards, followed by their updates. Deleted keys are returned as
+    // entries with a `Delete` or `Purge` operation.
+    pub fn watch<'t>(
+        &'t self,
+        client: &'t mut Client,
+        keys: &str,
+    ) -> Result<KvWatcher<'t>, NatsError> {
+        if !is_valid_key_pattern(keys) {
+            return Err(NatsError::from((
+                ErrorKind::ClientProtocolError,
+                "Invalid key",
+                keys.to_owned(),
+            )));
+        }
+        let mut consumer = OrderedConsumer::new(
+            &format!("KV_{}", self.bucket),
+            &format!("$KV.{}.{}", self.bucket, keys),
+            true,
+            self.timeout,
+        );
+        consumer.create(client)?;
+        Ok(KvWatcher {
+            kv: self,
+            client,
+            consumer,
+        })
+    }
+
+    // Marks a key as deleted
+    pub fn delete(&self, client: &mut Client, key: &str) -> Result<(), NatsError> {
+        let subject = self.subject(key)?;
+        let mut headers = Headers::new();
+        headers.insert(KV_OPERATION_HEADER, "DEL");
+        client.publish_with_headers(&subject, &headers, b"")
+    }
+
+    fn subject(&self, key: &str) -> Result<String, NatsError> {
+        if !is_valid_key(key) {
+            return Err(NatsError::from((
+                ErrorKind::ClientProtocolError,
+                "Invalid key",
+                key.to_owned(),
+            )));
+        }
+        Ok(format!("$KV.{}.{}", self.bucket, key))
+    }
+
+    fn entry_from_reply(&self, key: &str, reply: &Reply) -> Result<Entry, NatsError> {
+        let headers = reply.headers.as_ref();
+        let revision = headers
+            .and_then(|h| h.get(SEQUENCE_HEADER))
+            .and_then(|seq| seq.parse().ok())
+            .unwrap_or(0);
+        self.entry(key, headers, &reply.msg, revision)
+    }
+
+    // Decodes an entry, decrypting its value if a cipher was set
+    fn entry(
+        &self,
+        key: &str,
+        headers: Option<&Headers>,
+        msg: &Bytes,
+        revision: u64,
+    ) -> Result<Entry, NatsError> {
+        let operation = match headers.and_then(|h| h.get(KV_OPERATION_HEADER)) {
+            Some("DEL") => Operation::Delete,
+            Some("PURGE") => Operation::Purge,
+            _ => Operation::Put,
+        };
+        let value = match (&self.cipher, operation) {
+            (Some(cipher), Operation::Put) => Bytes::from(cipher.open(&self.subject(key)?, msg)?),
+            _ => msg.clone(),
+        };
+        Ok(Entry {
+            bucket: self.bucket.clone(),
+            key: key.to_owned(),
+            value,
+            revision,
+            operation,
+        })
+    }
+}
+
+// Updates of the keys of a bucket, delivered by an ordered consumer
+#[derive(Debug)]
+pub struct KvWatcher<'t> {
+    kv: &'t KeyValue,
+    client: &'t mut Client,
+    consumer: OrderedConsumer,
+}
+
+impl KvWatcher<'_> {
+    // Whether the entries that existed when the watcher was created have all
+    // been returned
+    pub fn is_caught_up(&self) -> bool {
+        self.consumer.is_caught_up()
+    }
+
+    // Returns the next entry, or `None` if there was no update before the
+    // timeout
+    pub fn next_timeout(&mut self, timeout: Duration) -> Result<Option<Entry>, NatsError> {
+        let (event, meta) = match self.consumer.next_timeout(self.client, timeout)? {
+            None => return Ok(None),
+            Some(delivery) => delivery,
+        };
+        let prefix_len = "$KV.".len() + self.kv.bucket.len() + 1;
+        let key = event.subject.get(prefix_len..).unwrap_or_default();
+        self.kv
+            .entry(key, event.headers.as_ref(), &event.msg, meta.stream_seq)
+            .map(Some)
+    }
+}
+
+impl Iterator for KvWatcher<'_> {
+    type Item = Result<Entry, NatsError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.next_timeout(Duration::from_secs(3600)) {
+                Ok(None) => continue,
+                res => return res.transpose(),
+            }
+        }
+    }
+}
+
+impl Drop for KvWatcher<'_> {
+    fn drop(&mut self) {
+        self.consumer.reset(self.client);
+    }
+}
+
+// Keys, possibly with `*` and `>` wildcards
+fn is_valid_key_pattern(keys: &str) -> bool {
+    let mut tokens = keys.split('.').peekable();
+    while let Some(token) = tokens.next() {
+        let valid = match token {
+            "*" => true,
+            ">" => tokens.peek().is_none(),
+            token => is_valid_key(token),
+        };
+        if !valid {
+            return false;
+        }
+    }
+    true
+}
+
+fn is_valid_key(key: &str) -> bool {
+    !key.is_empty()
+        && !key.starts_with('.')
+        && !key.ends_with('.')
+        && key
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || b"-/_=.".contains(&c))
+}
+
+#[test]
+fn kv_key_test() {
+    assert!(is_valid_key("a/b.c=d"));
+    assert!(!is_valid_key("a.b."));
+    assert!(!is_valid_key("a b"));
+    assert!(is_valid_key_pattern("db.*.url"));
+    assert!(is_valid_key_pattern(">"));
+    assert!(!is_valid_key_pattern("db.>.url"));
+    assert!(!is_valid_key_pattern("db..url"));
+}
@@ -1,10 +1,75 @@
+pub use bytes;
 pub use openssl;
 
+pub use crate::cache::*;
+pub use crate::capabilities::*;
 pub use crate::client::*;
+pub use crate::compression::*;
+pub use crate::crypto::*;
+pub use crate::encryption::*;
 pub use crate::errors::*;
+#[cfg(feature = "async")]
+pub use crate::event_stream::*;
+pub use crate::executor::*;
+pub use crate::headers::*;
+pub use crate::integrity::*;
+pub use crate::interceptor::*;
+pub use crate::jetstream::*;
+pub use crate::json::*;
+pub use crate::kv::*;
+pub use crate::metrics::*;
+pub use crate::nuid::*;
+pub use crate::object_store::*;
+pub use crate::options::*;
+pub use crate::pool::*;
+pub use crate::reconnect::*;
+pub use crate::reply::*;
+pub use crate::service::*;
+pub use crate::shared::*;
+pub use crate::socket_options::*;
+#[cfg(feature = "stan")]
+pub use crate::stan::*;
+pub use crate::subject::*;
 pub use crate::tls_config::*;
+pub use crate::topology::*;
+pub use crate::trace::*;
 
+pub mod bench;
+mod cache;
+mod capabilities;
 mod client;
+mod compression;
+mod crypto;
+mod encryption;
 mod errors;
+#[cfg(all(feature = "mio", unix))]
+mod event_loop;
+#[cfg(feature = "async")]
+mod event_stream;
+mod executor;
+mod headers;
+mod integrity;
+mod interceptor;
+mod jetstream;
+mod json;
+mod kv;
+mod metrics;
+mod nuid;
+mod object_store;
+mod options;
+mod pool;
+mod protocol;
+mod reconnect;
+mod reply;
+mod service;
+mod shared;
+mod socket_options;
+#[cfg(feature = "stan")]
+mod stan;
 mod stream;
+mod subject;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 mod tls_config;
+mod topology;
+mod trace;
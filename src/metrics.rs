@@ -0,0 +1,151 @@
+use std::{collections::HashMap, fmt, time::Duration};
+
+// Receives measurements from the client, to forward them to a metrics system
+// such as Prometheus or StatsD. Every method does nothing by default.
+pub trait MetricsSink: Send + Sync {
+    // Message published, with the size of its payload and headers
+    fn record_publish(&self, _subject: &str, _bytes: usize) {}
+
+    // Message received for a subscription
+    fn record_receive(&self, _subject: &str, _bytes: usize) {}
+
+    // Connection re-established, with the label of the new connection
+    fn record_reconnect(&self, _connection: &str) {}
+
+    // Time between sending a request and receiving its reply
+    fn record_request_latency(&self, _subject: &str, _latency: Duration) {}
+}
+
+impl fmt::Debug for dyn MetricsSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "MetricsSink {{}}")
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PublishCounters {
+    pub messages: u64,
+    // Payload and headers
+    pub bytes: u64,
+}
+
+impl PublishCounters {
+    fn add(&mut self, other: PublishCounters) {
+        self.messages += other.messages;
+        self.bytes += other.bytes;
+    }
+}
+
+// Messages published per subject, by decreasing number of bytes. Subjects
+// that didn't fit in the tracked set are accounted for in `other`.
+#[derive(Clone, Debug, Default)]
+pub struct PublishStats {
+    pub subjects: Vec<(String, PublishCounters)>,
+    pub other: PublishCounters,
+}
+
+#[derive(Debug)]
+struct SubjectEntry {
+    counters: PublishCounters,
+    last_seen: u64,
+}
+
+// Tracks at most `max_subjects` subjects. When a new subject is published
+// and the set is full, the subject with the least bytes (the least recently
+// published one on ties) is folded into `other`, so that the heaviest
+// subjects are kept while the memory usage stays bounded.
+#[derive(Debug)]
+pub(crate) struct PublishMetrics {
+    max_subjects: usize,
+    subjects: HashMap<String, SubjectEntry>,
+    other: PublishCounters,
+    seq: u64,
+}
+
+impl PublishMetrics {
+    pub fn new(max_subjects: usize) -> PublishMetrics {
+        PublishMetrics {
+            max_subjects,
+            subjects: HashMap::new(),
+            other: PublishCounters::default(),
+            seq: 0,
+        }
+    }
+
+    pub fn record(&mut self, subject: &str, bytes: usize) {
+        self.seq += 1;
+        let counters = PublishCounters {
+            messages: 1,
+            bytes: bytes as u64,
+        };
+        if let Some(entry) = self.subjects.get_mut(subject) {
+            entry.counters.add(counters);
+            entry.last_seen = self.seq;
+            return;
+        }
+        if self.max_subjects == 0 {
+            self.other.add(counters);
+            return;
+        }
+        if self.subjects.len() >= self.max_subjects {
+            let evicted = self
+                .subjects
+                .iter()
+                .min_by_key(|(_, entry)| (entry.counters.bytes, entry.last_seen))
+                .map(|(subject, _)| subject.clone());
+            if let Some(evicted) = evicted {
+                if let Some(entry) = self.subjects.remove(&evicted) {
+                    self.other.add(entry.counters);
+                }
+            }
+        }
+        self.subjects.insert(
+            subject.to_owned(),
+            SubjectEntry {
+                counters,
+                last_seen: self.seq,
+            },
+        );
+    }
+
+    pub fn stats(&self) -> PublishStats {
+        let mut subjects: Vec<(String, PublishCounters)> = self
+            .subjects
+            .iter()
+            .map(|(subject, entry)| (subject.clone(), entry.counters))
+            .collect();
+        subjects.sort_by(|a, b| b.1.bytes.cmp(&a.1.bytes).then_with(|| a.0.cmp(&b.0)));
+        PublishStats {
+            subjects,
+            other: self.other,
+        }
+    }
+}
+
+#[test]
+fn publish_metrics_test() {
+    let mut metrics = PublishMetrics::new(2);
+    metrics.record("a", 100);
+    metrics.record("a", 100);
+    metrics.record("b", 10);
+    metrics.record("c", 1);
+    metrics.record("d", 1);
+    let stats = metrics.stats();
+    assert_eq!(stats.subjects.len(), 2);
+    assert_eq!(stats.subjects[0].0, "a");
+    assert_eq!(
+        stats.subjects[0].1,
+        PublishCounters {
+            messages: 2,
+            bytes: 200
+        }
+    );
+    assert_eq!(stats.subjects[1].0, "d");
+    assert_eq!(
+        stats.other,
+        PublishCounters {
+            messages: 2,
+            bytes: 11
+        }
+    );
+}
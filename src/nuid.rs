@@ -0,0 +1,107 @@
+use rand::{rngs::StdRng, Rng, RngCore, SeedableRng};
+use std::fmt;
+
+const DIGITS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+const BASE: u64 = 62;
+const PRE_LEN: usize = 12;
+const SEQ_LEN: usize = 10;
+const MAX_SEQ: u64 = 839_299_365_868_340_224; // BASE^SEQ_LEN
+const MIN_INC: u64 = 33;
+const MAX_INC: u64 = 333;
+
+// Source of unique identifiers, used to name request inboxes
+pub trait IdGenerator: Send {
+    fn next_id(&mut self) -> String;
+}
+
+impl<F: FnMut() -> String + Send> IdGenerator for F {
+    fn next_id(&mut self) -> String {
+        self()
+    }
+}
+
+impl fmt::Debug for dyn IdGenerator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "IdGenerator {{}}")
+    }
+}
+
+// NUID: a random prefix followed by a sequence advanced by random increments
+pub struct Nuid {
+    rng: Box<dyn RngCore + Send>,
+    pre: [u8; PRE_LEN],
+    seq: u64,
+    inc: u64,
+}
+
+impl Nuid {
+    pub fn new() -> Nuid {
+        Nuid::with_rng(Box::new(StdRng::from_entropy()))
+    }
+
+    pub fn with_rng(rng: Box<dyn RngCore + Send>) -> Nuid {
+        let mut nuid = Nuid {
+            rng,
+            pre: [0; PRE_LEN],
+            seq: 0,
+            inc: 0,
+        };
+        nuid.randomize_prefix();
+        nuid.reset_sequence();
+        nuid
+    }
+
+    fn randomize_prefix(&mut self) {
+        for c in self.pre.iter_mut() {
+            *c = DIGITS[(self.rng.next_u32() as u64 % BASE) as usize];
+        }
+    }
+
+    fn reset_sequence(&mut self) {
+        self.seq = self.rng.gen_range(0, MAX_SEQ);
+        self.inc = self.rng.gen_range(MIN_INC, MAX_INC);
+    }
+}
+
+impl Default for Nuid {
+    fn default() -> Nuid {
+        Nuid::new()
+    }
+}
+
+impl IdGenerator for Nuid {
+    fn next_id(&mut self) -> String {
+        self.seq += self.inc;
+        if self.seq >= MAX_SEQ {
+            self.randomize_prefix();
+            self.reset_sequence();
+        }
+        let mut id = [0u8; PRE_LEN + SEQ_LEN];
+        id[..PRE_LEN].copy_from_slice(&self.pre);
+        let mut seq = self.seq;
+        for c in id[PRE_LEN..].iter_mut().rev() {
+            *c = DIGITS[(seq % BASE) as usize];
+            seq /= BASE;
+        }
+        id.iter().map(|&c| c as char).collect()
+    }
+}
+
+impl fmt::Debug for Nuid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Nuid {{}}")
+    }
+}
+
+#[test]
+fn nuid_test() {
+    let mut a = Nuid::with_rng(Box::new(StdRng::seed_from_u64(42)));
+    let mut b = Nuid::with_rng(Box::new(StdRng::seed_from_u64(42)));
+    let id1 = a.next_id();
+    let id2 = a.next_id();
+    assert_eq!(id1.len(), PRE_LEN + SEQ_LEN);
+    assert_ne!(id1, id2);
+    assert_eq!(id1[..PRE_LEN], id2[..PRE_LEN]);
+    assert_eq!(id1, b.next_id());
+    assert!(id1.bytes().all(|c| c.is_ascii_alphanumeric()));
+}
@@ -0,0 +1,461 @@
+use crate::client::Client;
+use crate::errors::*;
+use crate::headers::Headers;
+use crate::jetstream::OrderedConsumer;
+use crate::nuid::{IdGenerator, Nuid};
+use crate::reply::{Reply, StatusCode};
+use bytes::Bytes;
+use openssl::{base64, sha::Sha256};
+use serde_json::{json, Value};
+use std::{cmp, fmt, io, time::Duration};
+
+const DEFAULT_OBJECT_TIMEOUT_MS: u64 = 5000;
+const DEFAULT_CHUNK_SIZE: usize = 128 * 1024;
+const ROLLUP_HEADER: &str = "Nats-Rollup";
+const DIGEST_PREFIX: &str = "SHA-256=";
+
+// Description of an object, stored in the bucket alongside its chunks
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ObjectInfo {
+    pub bucket: String,
+    pub name: String,
+    // Identifier of the chunks of the current version of the object
+    pub nuid: String,
+    pub size: u64,
+    pub chunks: u64,
+    pub digest: String,
+    pub deleted: bool,
+}
+
+impl ObjectInfo {
+    fn from_json(info: &Value) -> Option<ObjectInfo> {
+        let string = |field: &str| info.get(field).and_then(|v| v.as_str()).map(str::to_owned);
+        let number = |field: &str| info.get(field).and_then(|v| v.as_u64()).unwrap_or(0);
+        Some(ObjectInfo {
+            bucket: string("bucket")?,
+            name: string("name")?,
+            nuid: string("nuid")?,
+            size: number("size"),
+            chunks: number("chunks"),
+            digest: string("digest").unwrap_or_default(),
+            deleted: info.get("deleted").and_then(|v| v.as_bool()) == Some(true),
+        })
+    }
+
+    fn to_json(&self, chunk_size: usize) -> Value {
+        let mut info = json!({
+            "bucket": self.bucket,
+            "name": self.name,
+            "nuid": self.nuid,
+            "size": self.size,
+            "chunks": self.chunks,
+            "digest": self.digest,
+            "options": { "max_chunk_size": chunk_size },
+        });
+        if self.deleted {
+            info["deleted"] = json!(true);
+        }
+        info
+    }
+}
+
+// Access to an existing JetStream object store bucket. Objects are split into
+// chunks, so that they can be written and read without being entirely loaded
+// in memory.
+#[derive(Debug)]
+pub struct ObjectStore {
+    bucket: String,
+    timeout: Duration,
+    chunk_size: usize,
+}
+
+impl ObjectStore {
+    pub fn new(bucket: &str) -> Result<ObjectStore, NatsError> {
+        if bucket.is_empty()
+            || !bucket
+                .bytes()
+                .all(|c| c.is_ascii_alphanumeric() || c == b'_' || c == b'-')
+        {
+            return Err(NatsError::from((
+                ErrorKind::InvalidClientConfig,
+                "Invalid bucket name",
+                bucket.to_owned(),
+            )));
+        }
+        Ok(ObjectStore {
+            bucket: bucket.to_owned(),
+            timeout: Duration::from_millis(DEFAULT_OBJECT_TIMEOUT_MS),
+            chunk_size: DEFAULT_CHUNK_SIZE,
+        })
+    }
+
+    pub fn bucket(&self) -> &str {
+        &self.bucket
+    }
+
+    // Timeout of each request sent to the server, and of each chunk read
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    // Size of the chunks written by `put()`, also the most memory it uses
+    pub fn set_chunk_size(&mut self, chunk_size: usize) -> Result<(), NatsError> {
+        if chunk_size == 0 {
+            return Err(NatsError::from((
+                ErrorKind::InvalidClientConfig,
+                "The chunk size cannot be zero",
+            )));
+        }
+        self.chunk_size = chunk_size;
+        Ok(())
+    }
+
+    // Stores an object read from `reader`, replacing the previous version.
+    // Chunks are sent as soon as they are read, each one being acknowledged
+    // before the next one is read.
+    pub fn put<R: io::Read>(
+        &self,
+        client: &mut Client,
+        name: &str,
+        mut reader: R,
+    ) -> Result<ObjectInfo, NatsError> {
+        let meta_subject = self.meta_subject(name)?;
+        let previous = self.info(client, name)?;
+        let nuid = Nuid::new().next_id();
+        let chunk_subject = self.chunk_subject(&nuid);
+        let mut info = ObjectInfo {
+            bucket: self.bucket.clone(),
+            name: name.to_owned(),
+            nuid,
+            size: 0,
+            chunks: 0,
+            digest: String::new(),
+            deleted: false,
+        };
+        let mut hasher = Sha256::new();
+        let mut chunk = vec![0u8; self.chunk_size];
+        let res = (|| -> Result<(), NatsError> {
+            loop {
+                let len = read_chunk(&mut reader, &mut chunk)?;
+                if len == 0 {
+                    break;
+                }
+                hasher.update(&chunk[..len]);
+                let reply = client.request(&chunk_subject, &chunk[..len], self.timeout)?;
+                check_ack(&reply)?;
+                info.size += len as u64;
+                info.chunks += 1;
+            }
+            info.digest = format!("{}{}", DIGEST_PREFIX, base64_url(&hasher.finish()));
+            self.put_meta(client, &meta_subject, &info)
+        })();
+        if let Err(e) = res {
+            self.purge(client, &chunk_subject);
+            return Err(e);
+        }
+        if let Some(previous) = previous {
+            self.purge(client, &self.chunk_subject(&previous.nuid));
+        }
+        Ok(info)
+    }
+
+    // Returns the description of an object, or `None` if it doesn't exist or
+    // was deleted. Requires a bucket allowing direct gets.
+    pub fn info(&self, client: &mut Client, name: &str) -> Result<Option<ObjectInfo>, NatsError> {
+        let meta_subject = self.meta_subject(name)?;
+        let api = format!("$JS.API.DIRECT.GET.OBJ_{}.{}", self.bucket, meta_subject);
+        let reply = client.request(&api, b"", self.timeout)?;
+        if reply.status == Some(StatusCode::NotFound) {
+            return Ok(None);
+        }
+        let info: Value = reply.decode_json()?;
+        let info = ObjectInfo::from_json(&info).ok_or_else(|| {
+            NatsError::from((
+                ErrorKind::ServerProtocolError,
+                "Invalid object description",
+                info.to_string(),
+            ))
+        })?;
+        if info.deleted {
+            return Ok(None);
+        }
+        Ok(Some(info))
+    }
+
+    // Returns a reader for the content of an object, or `None` if it doesn't
+    // exist. Chunks are retrieved as the reader consumes them, and the digest
+    // is verified once the last one was read.
+    pub fn get<'t>(
+        &'t self,
+        client: &'t mut Client,
+        name: &str,
+    ) -> Result<Option<ObjectReader<'t>>, NatsError> {
+        let info = match self.info(client, name)? {
+            None => return Ok(None),
+            Some(info) => info,
+        };
+        let mut consumer = OrderedConsumer::new(
+            &format!("OBJ_{}", self.bucket),
+            &self.chunk_subject(&info.nuid),
+            false,
+            self.timeout,
+        );
+        if info.size > 0 {
+            consumer.create(client)?;
+        }
+        Ok(Some(ObjectReader {
+            store: self,
+            client,
+            info,
+            consumer,
+            chunk: Bytes::new(),
+            received: 0,
+            hasher: Some(Sha256::new()),
+        }))
+    }
+
+    // Marks an object as deleted, and removes its chunks
+    pub fn delete(&self, client: &mut Client, name: &str) -> Result<(), NatsError> {
+        let meta_subject = self.meta_subject(name)?;
+        let mut info = match self.info(client, name)? {
+            None => return Ok(()),
+            Some(info) => info,
+        };
+        let chunk_subject = self.chunk_subject(&info.nuid);
+        info.size = 0;
+        info.chunks = 0;
+        info.digest = String::new();
+        info.deleted = true;
+        self.put_meta(client, &meta_subject, &info)?;
+        self.purge(client, &chunk_subject);
+        Ok(())
+    }
+
+    // Replaces the previous description of the object, if any
+    fn put_meta(
+        &self,
+        client: &mut Client,
+        meta_subject: &str,
+        info: &ObjectInfo,
+    ) -> Result<(), NatsError> {
+        let mut headers = Headers::new();
+        headers.insert(ROLLUP_HEADER, "sub");
+        let meta = info.to_json(self.chunk_size).to_string();
+        let inbox = client.new_inbox();
+        let channel = client.subscribe(&inbox, None)?;
+        let res = client
+            .publish_with_inbox_and_headers(meta_subject, &inbox, &headers, meta.as_bytes())
+            .and_then(|_| client.select(&[channel], self.timeout));
+        let _ = client.unsubscribe(channel);
+        let reply = res?.map(Reply::from).ok_or_else(|| {
+            NatsError::from((
+                ErrorKind::Timeout,
+                "No acknowledgment received before the timeout",
+                meta_subject.to_owned(),
+            ))
+        })?;
+        check_ack(&reply).map(|_| ())
+    }
+
+    // Removes chunks that are not referenced any more. Failures only leave
+    // unused chunks behind, and are ignored.
+    fn purge(&self, client: &mut Client, chunk_subject: &str) {
+        let api = format!("$JS.API.STREAM.PURGE.OBJ_{}", self.bucket);
+        let request = json!({ "filter": chunk_subject });
+        let _ = client.request(&api, request.to_string().as_bytes(), self.timeout);
+    }
+
+    fn meta_subject(&self, name: &str) -> Result<String, NatsError> {
+        if name.is_empty() {
+            return Err(NatsError::from((
+                ErrorKind::ClientProtocolError,
+                "Object names cannot be empty",
+            )));
+        }
+        Ok(format!(
+            "$O.{}.M.{}",
+            self.bucket,
+            base64_url(name.as_bytes())
+        ))
+    }
+
+    fn chunk_subject(&self, nuid: &str) -> String {
+        format!("$O.{}.C.{}", self.bucket, nuid)
+    }
+}
+
+// Content of an object, read chunk by chunk
+pub struct ObjectReader<'t> {
+    store: &'t ObjectStore,
+    client: &'t mut Client,
+    info: ObjectInfo,
+    consumer: OrderedConsumer,
+    // Remaining bytes of the current chunk
+    chunk: Bytes,
+    received: u64,
+    // Taken once the whole object was received
+    hasher: Option<Sha256>,
+}
+
+impl ObjectReader<'_> {
+    pub fn info(&self) -> &ObjectInfo {
+        &self.info
+    }
+
+    fn next_chunk(&mut self) -> Result<(), NatsError> {
+        let (event, _) = self
+            .consumer
+            .next_timeout(self.client, self.store.timeout)?
+            .ok_or_else(|| {
+                NatsError::from((
+                    ErrorKind::Timeout,
+                    "No chunk received before the timeout",
+                    self.info.name.clone(),
+                ))
+            })?;
+        self.received += event.msg.len() as u64;
+        if self.received > self.info.size {
+            return Err(NatsError::from((
+                ErrorKind::IntegrityError,
+                "The object is larger than its description",
+                self.info.name.clone(),
+            )));
+        }
+        if let Some(ref mut hasher) = self.hasher {
+            hasher.update(&event.msg);
+        }
+        self.chunk = event.msg;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), NatsError> {
+        let hasher = match self.hasher.take() {
+            None => return Ok(()),
+            Some(hasher) => hasher,
+        };
+        self.consumer.reset(self.client);
+        let digest = format!("{}{}", DIGEST_PREFIX, base64_url(&hasher.finish()));
+        if digest != self.info.digest {
+            return Err(NatsError::from((
+                ErrorKind::IntegrityError,
+                "Digest mismatch",
+                self.info.name.clone(),
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl io::Read for ObjectReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.chunk.is_empty() {
+            if self.received == self.info.size {
+                self.finish().map_err(io_error)?;
+                return Ok(0);
+            }
+            self.next_chunk().map_err(io_error)?;
+        }
+        let len = cmp::min(buf.len(), self.chunk.len());
+        buf[..len].copy_from_slice(&self.chunk.split_to(len));
+        Ok(len)
+    }
+}
+
+impl fmt::Debug for ObjectReader<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "ObjectReader {{ info: {:?}, received: {} }}",
+            self.info, self.received
+        )
+    }
+}
+
+impl Drop for ObjectReader<'_> {
+    fn drop(&mut self) {
+        self.consumer.reset(self.client);
+    }
+}
+
+// Fills `chunk`, unless the end of the input is reached first
+fn read_chunk<R: io::Read>(reader: &mut R, chunk: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < chunk.len() {
+        match reader.read(&mut chunk[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
+fn check_ack(reply: &Reply) -> Result<u64, NatsError> {
+    let ack: Value = reply.decode_json()?;
+    if let Some(error) = ack.get("error") {
+        return Err(NatsError::from((
+            ErrorKind::ServerProtocolError,
+            "Chunk not stored",
+            error.to_string(),
+        )));
+    }
+    ack.get("seq").and_then(|seq| seq.as_u64()).ok_or_else(|| {
+        NatsError::from((
+            ErrorKind::ServerProtocolError,
+            "Invalid acknowledgment",
+            ack.to_string(),
+        ))
+    })
+}
+
+fn io_error(e: NatsError) -> io::Error {
+    let kind = match e.kind() {
+        ErrorKind::Timeout => io::ErrorKind::TimedOut,
+        ErrorKind::IntegrityError => io::ErrorKind::InvalidData,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, e)
+}
+
+// Base64 with the URL-safe alphabet, as used for object names and digests
+fn base64_url(data: &[u8]) -> String {
+    base64::encode_block(data)
+        .chars()
+        .map(|c| match c {
+            '+' => '-',
+            '/' => '_',
+            c => c,
+        })
+        .collect()
+}
+
+#[test]
+fn object_store_test() {
+    let info = ObjectInfo {
+        bucket: "files".to_owned(),
+        name: "backup.tar".to_owned(),
+        nuid: "abc".to_owned(),
+        size: 300,
+        chunks: 3,
+        digest: "SHA-256=x".to_owned(),
+        deleted: false,
+    };
+    assert_eq!(ObjectInfo::from_json(&info.to_json(128)), Some(info));
+    assert_eq!(base64_url(&[0xfb, 0xff]), "-_8=");
+
+    let store = ObjectStore::new("files").unwrap();
+    assert_eq!(
+        store.meta_subject("a?b").unwrap(),
+        format!("$O.files.M.{}", base64_url(b"a?b"))
+    );
+    assert!(store.meta_subject("").is_err());
+
+    // Short reads are coalesced into full chunks
+    let mut reader = io::Read::chain(&[1u8, 2][..], &[3u8, 4, 5][..]);
+    let mut chunk = [0u8; 4];
+    assert_eq!(read_chunk(&mut reader, &mut chunk).unwrap(), 4);
+    assert_eq!(chunk, [1, 2, 3, 4]);
+    assert_eq!(read_chunk(&mut reader, &mut chunk).unwrap(), 1);
+    assert_eq!(read_chunk(&mut reader, &mut chunk).unwrap(), 0);
+}
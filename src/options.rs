@@ -0,0 +1,134 @@
+use crate::client::{Client, ToStringVec};
+use crate::errors::*;
+use crate::reconnect::{CircuitBreakerPolicy, ReconnectPolicy};
+use crate::tls_config::TlsConfigBuilder;
+use openssl::{pkey::PKey, x509::X509};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+const DEFAULT_MAX_RECONNECTS: usize = 60;
+const DEFAULT_RECONNECT_WAIT_MS: u64 = 2000;
+const DEFAULT_PING_INTERVAL_MS: u64 = 120_000;
+const DEFAULT_MAX_PINGS_OUT: u32 = 2;
+
+// Connection options named after, and defaulting to the values of, the
+// official clients (nats.go, nats.rs), so that existing configurations can be
+// reused as-is.
+// `max_reconnects` is the number of times an operation is retried after a
+// connection failure, as well as the number of rounds of connection attempts
+// to every server before giving up, `None` retrying forever. Certificates and
+// keys are PEM files.
+#[derive(Clone, Debug)]
+pub struct CompatOptions {
+    pub max_reconnects: Option<usize>,
+    pub reconnect_wait: Duration,
+    pub ping_interval: Option<Duration>,
+    pub max_pings_out: u32,
+    pub ignore_discovered_servers: bool,
+    pub client_cert: Option<(PathBuf, PathBuf)>,
+    pub root_certificates: Vec<PathBuf>,
+}
+
+impl Default for CompatOptions {
+    fn default() -> CompatOptions {
+        CompatOptions {
+            max_reconnects: Some(DEFAULT_MAX_RECONNECTS),
+            reconnect_wait: Duration::from_millis(DEFAULT_RECONNECT_WAIT_MS),
+            ping_interval: Some(Duration::from_millis(DEFAULT_PING_INTERVAL_MS)),
+            max_pings_out: DEFAULT_MAX_PINGS_OUT,
+            ignore_discovered_servers: false,
+            client_cert: None,
+            root_certificates: Vec::new(),
+        }
+    }
+}
+
+impl CompatOptions {
+    fn reconnect_policy(&self) -> ReconnectPolicy {
+        ReconnectPolicy {
+            max_attempts: self
+                .max_reconnects
+                .map(|max| max.saturating_add(1).min(u32::MAX as usize) as u32),
+            base_delay: self.reconnect_wait,
+            max_delay: self.reconnect_wait,
+            multiplier: 1.0,
+            jitter: 0.0,
+        }
+    }
+
+    fn circuit_breaker_policy(&self) -> CircuitBreakerPolicy {
+        CircuitBreakerPolicy {
+            rounds_before_breaking: self
+                .max_reconnects
+                .map_or(u32::MAX, |max| max.clamp(1, u32::MAX as usize) as u32),
+            ..CircuitBreakerPolicy::default()
+        }
+    }
+}
+
+impl Client {
+    // Creates a client configured with options of the official clients
+    pub fn options_compat<T: ToStringVec>(
+        uris: T,
+        options: &CompatOptions,
+    ) -> Result<Client, NatsError> {
+        let mut client = Client::new(uris)?;
+        client.set_reconnect_policy(options.reconnect_policy());
+        client.set_circuit_breaker_policy(options.circuit_breaker_policy());
+        client.set_ping_interval(options.ping_interval);
+        client.set_max_outstanding_pings(options.max_pings_out);
+        client.set_discover_servers(!options.ignore_discovered_servers);
+        if options.client_cert.is_some() || !options.root_certificates.is_empty() {
+            let mut builder = TlsConfigBuilder::new()?;
+            for path in &options.root_certificates {
+                builder.add_root_certificate(X509::from_pem(&read_pem(path)?)?)?;
+            }
+            if let Some((ref cert_path, ref key_path)) = options.client_cert {
+                let cert = X509::from_pem(&read_pem(cert_path)?)?;
+                let key = PKey::private_key_from_pem(&read_pem(key_path)?)?;
+                builder.add_client_certificate(&cert, &key)?;
+            }
+            client.set_tls_config(builder.build());
+        }
+        Ok(client)
+    }
+}
+
+fn read_pem(path: &Path) -> Result<Vec<u8>, NatsError> {
+    fs::read(path).map_err(|e| {
+        NatsError::from((
+            ErrorKind::InvalidClientConfig,
+            "Unable to read a certificate or key file",
+            format!("{}: {}", path.display(), e),
+        ))
+    })
+}
+
+#[test]
+fn compat_options_test() {
+    let policy = CompatOptions::default().reconnect_policy();
+    assert_eq!(policy.max_attempts, Some(61));
+    assert_eq!(policy.delay(5), Duration::from_secs(2));
+    let breaker = CompatOptions::default().circuit_breaker_policy();
+    assert_eq!(breaker.rounds_before_breaking, 60);
+
+    let options = CompatOptions {
+        max_reconnects: None,
+        ..CompatOptions::default()
+    };
+    assert_eq!(options.reconnect_policy().max_attempts, None);
+    assert_eq!(
+        options.circuit_breaker_policy().rounds_before_breaking,
+        u32::MAX
+    );
+
+    let options = CompatOptions {
+        root_certificates: vec![PathBuf::from("/nonexistent/ca.pem")],
+        ..CompatOptions::default()
+    };
+    let err = Client::options_compat("nats://127.0.0.1", &options).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidClientConfig);
+}
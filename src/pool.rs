@@ -0,0 +1,297 @@
+use crate::client::Client;
+use crate::errors::*;
+use crate::headers::Headers;
+use log::warn;
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+// Creates the clients of a pool, all configured the same way
+pub trait ClientFactory: Send {
+    fn create(&mut self) -> Result<Client, NatsError>;
+}
+
+impl<F: FnMut() -> Result<Client, NatsError> + Send> ClientFactory for F {
+    fn create(&mut self) -> Result<Client, NatsError> {
+        self()
+    }
+}
+
+impl fmt::Debug for dyn ClientFactory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ClientFactory {{}}")
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PoolMemberStatus {
+    // Whether the member currently has a client, and is connected
+    pub connected: bool,
+    // Operations that failed because of the connection
+    pub failures: u64,
+    // Clients created to replace failed ones
+    pub replacements: u64,
+}
+
+// Connections to the same cluster, used in turn by publishers, as a single
+// connection limits the throughput. A member whose connection failed is
+// replaced with a new client the next time it is picked.
+#[derive(Debug)]
+pub struct ClientPool {
+    members: Vec<Mutex<Member>>,
+    next: AtomicUsize,
+    factory: Mutex<Box<dyn ClientFactory>>,
+    closed: AtomicBool,
+}
+
+#[derive(Debug)]
+struct Member {
+    client: Option<Client>,
+    status: PoolMemberStatus,
+}
+
+impl ClientPool {
+    pub fn new<F: ClientFactory + 'static>(
+        size: usize,
+        mut factory: F,
+    ) -> Result<ClientPool, NatsError> {
+        if size == 0 {
+            return Err(NatsError::from((
+                ErrorKind::InvalidClientConfig,
+                "A pool requires at least one client",
+            )));
+        }
+        let mut members = Vec::with_capacity(size);
+        for _ in 0..size {
+            members.push(Mutex::new(Member {
+                client: Some(factory.create()?),
+                status: PoolMemberStatus::default(),
+            }));
+        }
+        Ok(ClientPool {
+            members,
+            next: AtomicUsize::new(0),
+            factory: Mutex::new(Box::new(factory)),
+            closed: AtomicBool::new(false),
+        })
+    }
+
+    pub fn size(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn publish(&self, subject: &str, msg: &[u8]) -> Result<(), NatsError> {
+        self.with_member(|client| client.publish(subject, msg))
+    }
+
+    pub fn publish_with_headers(
+        &self,
+        subject: &str,
+        headers: &Headers,
+        msg: &[u8],
+    ) -> Result<(), NatsError> {
+        self.with_member(|client| client.publish_with_headers(subject, headers, msg))
+    }
+
+    // Flushes every member, returning the first error
+    pub fn flush(&self) -> Result<(), NatsError> {
+        let mut res = Ok(());
+        for idx in 0..self.members.len() {
+            if let Err(e) = self.with_member_at(idx, |client| client.flush()) {
+                if res.is_ok() {
+                    res = Err(e);
+                }
+            }
+        }
+        res
+    }
+
+    pub fn status(&self) -> Vec<PoolMemberStatus> {
+        self.members
+            .iter()
+            .map(|member| {
+                let member = member.lock().unwrap();
+                PoolMemberStatus {
+                    connected: member
+                        .client
+                        .as_ref()
+                        .is_some_and(|client| client.is_connected()),
+                    ..member.status.clone()
+                }
+            })
+            .collect()
+    }
+
+    // Closes every member. Operations then fail, instead of creating new
+    // clients.
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        for member in &self.members {
+            if let Some(mut client) = member.lock().unwrap().client.take() {
+                let _ = client.close();
+            }
+        }
+    }
+
+    // Runs `f` on the next member. If the connection fails, the error is
+    // returned and the member is dropped. The operation isn't retried on
+    // another member, as the message may have been sent, and each member
+    // already waits for its own reconnection attempts.
+    fn with_member<F, T>(&self, f: F) -> Result<T, NatsError>
+    where
+        F: Fn(&mut Client) -> Result<T, NatsError>,
+    {
+        let idx = self.next.fetch_add(1, Ordering::Relaxed) % self.members.len();
+        self.with_member_at(idx, f)
+    }
+
+    fn with_member_at<F, T>(&self, idx: usize, f: F) -> Result<T, NatsError>
+    where
+        F: Fn(&mut Client) -> Result<T, NatsError>,
+    {
+        let mut member = self.members[idx].lock().unwrap();
+        if self.closed.load(Ordering::Relaxed) {
+            return Err(NatsError::from((
+                ErrorKind::ConnectionClosed,
+                "The pool was closed",
+            )));
+        }
+        if member.client.is_none() {
+            member.client = Some(self.factory.lock().unwrap().create()?);
+            member.status.replacements += 1;
+        }
+        let res = f(member.client.as_mut().unwrap());
+        if let Err(ref e) = res {
+            if connection_failed(e) {
+                warn!("Pool member {} failed, it will be replaced: {}", idx, e);
+                member.status.failures += 1;
+                if let Some(mut client) = member.client.take() {
+                    let _ = client.close();
+                }
+            }
+        }
+        res
+    }
+}
+
+impl Drop for ClientPool {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+// Errors that leave a client unusable, as opposed to errors caused by the
+// operation itself. Timeouts come from a full buffer or a slow server, and
+// are returned to the caller without replacing the client.
+fn connection_failed(e: &NatsError) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::IoError
+            | ErrorKind::ConnectionClosed
+            | ErrorKind::TlsError
+            | ErrorKind::AuthorizationViolation
+            | ErrorKind::ServerProtocolError
+    )
+}
+
+#[test]
+fn client_pool_test() {
+    let pool = ClientPool::new(3, || Client::new("nats://127.0.0.1:1")).unwrap();
+    assert_eq!(pool.size(), 3);
+    assert!(ClientPool::new(0, || Client::new("nats://127.0.0.1:1")).is_err());
+    assert!(pool.status().iter().all(|status| !status.connected));
+
+    assert!(connection_failed(&NatsError::from((
+        ErrorKind::IoError,
+        "I/O error"
+    ))));
+    assert!(!connection_failed(&NatsError::from((
+        ErrorKind::Timeout,
+        "Timeout"
+    ))));
+}
+
+#[test]
+fn client_pool_members_test() {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (lines, received) = mpsc::channel();
+    thread::spawn(move || {
+        for tcp in listener.incoming() {
+            let mut tcp = tcp.unwrap();
+            let lines = lines.clone();
+            thread::spawn(move || {
+                tcp.write_all(b"INFO {\"max_payload\":1048576}\r\n")
+                    .unwrap();
+                let reader = BufReader::new(tcp.try_clone().unwrap());
+                let mut name = String::new();
+                for line in reader.lines() {
+                    let line = match line {
+                        Ok(line) => line,
+                        Err(_) => break,
+                    };
+                    if let Some(connect) = line.strip_prefix("CONNECT ") {
+                        let connect: serde_json::Value = serde_json::from_str(connect).unwrap();
+                        name = connect["name"].as_str().unwrap().to_owned();
+                    } else if line == "PING" {
+                        let _ = tcp.write_all(b"PONG\r\n");
+                    } else if line.starts_with("PUB ") {
+                        let subject = line.split(' ').nth(1).unwrap().to_owned();
+                        let _ = lines.send((name.clone(), subject));
+                    }
+                }
+            });
+        }
+    });
+
+    // The second client can't connect, its replacement can
+    let created = Arc::new(AtomicUsize::new(0));
+    let created_ = created.clone();
+    let pool = ClientPool::new(2, move || {
+        let n = created_.fetch_add(1, Ordering::Relaxed);
+        let url = match n {
+            1 => "nats://127.0.0.1:1".to_owned(),
+            _ => format!("nats://127.0.0.1:{}", port),
+        };
+        let mut client = Client::new(url.as_str())?;
+        client.set_name(&format!("client-{}", n));
+        Ok(client)
+    })
+    .unwrap();
+    assert_eq!(created.load(Ordering::Relaxed), 2);
+
+    pool.publish("a", b"").unwrap();
+    assert!(connection_failed(&pool.publish("b", b"").unwrap_err()));
+    let status = pool.status();
+    assert_eq!(status[1].failures, 1);
+    assert!(!status[1].connected);
+    pool.publish("c", b"").unwrap();
+    pool.publish("d", b"").unwrap();
+    assert_eq!(created.load(Ordering::Relaxed), 3);
+    assert_eq!(pool.status()[1].replacements, 1);
+
+    let mut published: Vec<(String, String)> = (0..3).map(|_| received.recv().unwrap()).collect();
+    published.sort();
+    let expected = [("client-0", "a"), ("client-0", "c"), ("client-2", "d")];
+    let expected: Vec<(String, String)> = expected
+        .iter()
+        .map(|&(name, subject)| (name.to_owned(), subject.to_owned()))
+        .collect();
+    assert_eq!(published, expected);
+    // The failed message wasn't published through another member
+    assert!(received.try_recv().is_err());
+
+    pool.close();
+    let e = pool.publish("e", b"").unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::ConnectionClosed);
+    assert_eq!(created.load(Ordering::Relaxed), 3);
+}
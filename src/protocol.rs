@@ -0,0 +1,374 @@
+use crate::errors::*;
+use std::str;
+
+// Operation sent by the server, borrowing from the control line
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ServerOp<'l> {
+    Msg(MsgArgs<'l>),
+    // JSON object following `INFO`
+    Info(&'l str),
+    Ping,
+    Pong,
+    Ok,
+    // The whole `-ERR` line, as expected by `server_error()`
+    Err(&'l str),
+    Unknown(&'l str),
+}
+
+// Arguments of a `MSG` or `HMSG` line
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct MsgArgs<'l> {
+    pub(crate) subject: &'l str,
+    pub(crate) sid: u64,
+    pub(crate) reply: Option<&'l str>,
+    // Only set for `HMSG`
+    pub(crate) headers_len: Option<usize>,
+    // Length of the headers and the payload, without the trailing CRLF
+    pub(crate) len: usize,
+}
+
+// subject, sid, reply, headers length, total length
+const MAX_MSG_ARGS: usize = 5;
+
+// Sanity bound on the length of the messages sent by the server, checked
+// before anything is allocated for them. Servers of a cluster or leafnodes
+// can announce different `max_payload` values, so that one isn't used.
+const MAX_MSG_LEN: usize = 64 * 1024 * 1024;
+
+// Parses a control line, including its line terminator. `strict` rejects
+// lines not terminated by CRLF, and MSG lines with unexpected arguments.
+pub(crate) fn parse_control_line(line: &[u8], strict: bool) -> Result<ServerOp<'_>, NatsError> {
+    let line = str::from_utf8(line)?;
+    if line.len() < "+OK\r\n".len() {
+        return Err(NatsError::from((
+            ErrorKind::ServerProtocolError,
+            "Incomplete server response",
+        )));
+    }
+    if strict && !line.ends_with("\r\n") {
+        return Err(NatsError::from((
+            ErrorKind::ServerProtocolError,
+            "Control line not terminated by CRLF",
+            line.to_owned(),
+        )));
+    }
+    if line.starts_with("MSG ") || line.starts_with("HMSG ") {
+        return parse_msg_args(line, strict).map(ServerOp::Msg);
+    }
+    if let Some(info) = line.strip_prefix("INFO ") {
+        return Ok(ServerOp::Info(info));
+    }
+    if line.starts_with("-ERR") {
+        return Ok(ServerOp::Err(line));
+    }
+    Ok(match line.trim_end_matches(['\r', '\n']) {
+        "+OK" => ServerOp::Ok,
+        "PING" => ServerOp::Ping,
+        "PONG" => ServerOp::Pong,
+        _ => ServerOp::Unknown(line),
+    })
+}
+
+fn parse_msg_args(line: &str, strict: bool) -> Result<MsgArgs<'_>, NatsError> {
+    let with_headers = line.starts_with("HMSG ");
+    if line.len() < "MSG _ _ _\r\n".len() {
+        return Err(NatsError::from((
+            ErrorKind::ServerProtocolError,
+            "Incomplete server response",
+            line.to_owned(),
+        )));
+    }
+    let line = line.trim_end();
+    let mut args = [""; MAX_MSG_ARGS];
+    let mut count = 0;
+    for arg in line.split(' ').skip(1) {
+        if count < MAX_MSG_ARGS {
+            args[count] = arg;
+        }
+        count += 1;
+    }
+    let min_args = if with_headers { 4 } else { 3 };
+    if count < min_args {
+        return Err(NatsError::from((
+            ErrorKind::ServerProtocolError,
+            "Unsupported server response",
+            line.to_owned(),
+        )));
+    }
+    if strict && count > min_args + 1 {
+        return Err(NatsError::from((
+            ErrorKind::ServerProtocolError,
+            "Too many arguments in MSG",
+            line.to_owned(),
+        )));
+    }
+    let sid = match args[1].parse() {
+        Ok(sid) => sid,
+        Err(_) if strict => {
+            return Err(NatsError::from((
+                ErrorKind::ServerProtocolError,
+                "Invalid subscription identifier",
+                line.to_owned(),
+            )))
+        }
+        Err(_) => 0,
+    };
+    let (reply, lens) = if count > min_args {
+        (Some(args[2]), &args[3..=min_args])
+    } else {
+        (None, &args[2..min_args])
+    };
+    let parse_len = |len_s: &str| -> Result<usize, NatsError> {
+        len_s.parse().map_err(|_| {
+            NatsError::from((
+                ErrorKind::ServerProtocolError,
+                "Suspicous message length",
+                format!("{} (len: [{}])", line, len_s),
+            ))
+        })
+    };
+    let len = parse_len(lens[lens.len() - 1])?;
+    if len > MAX_MSG_LEN {
+        return Err(NatsError::from((
+            ErrorKind::ServerProtocolError,
+            "Suspicous message length",
+            format!("{} (maximum: {})", line, MAX_MSG_LEN),
+        )));
+    }
+    let headers_len = if with_headers {
+        Some(parse_len(lens[0])?)
+    } else {
+        None
+    };
+    if headers_len.is_some_and(|headers_len| headers_len > len) {
+        return Err(NatsError::from((
+            ErrorKind::ServerProtocolError,
+            "Headers longer than the message",
+            line.to_owned(),
+        )));
+    }
+    Ok(MsgArgs {
+        subject: args[0],
+        sid,
+        reply,
+        headers_len,
+        len,
+    })
+}
+
+// Frame parsed out of a buffer by a `Parser`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Frame<'b> {
+    pub(crate) op: ServerOp<'b>,
+    pub(crate) headers: Option<&'b [u8]>,
+    pub(crate) payload: &'b [u8],
+    // Number of bytes of the buffer the frame used
+    pub(crate) len: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ParserState {
+    // Bytes already searched for the end of the control line
+    ControlLine { scanned: usize },
+    // The control line was parsed, and the frame is `frame_len` bytes long
+    Payload { frame_len: usize },
+}
+
+// Incremental parser for the frames sent by the server. The buffer passed to
+// `parse()` must start with the frame being parsed, and only grow until the
+// frame is returned, so that the bytes scanned by previous calls are not
+// scanned again. Frames borrow from the buffer, nothing is copied.
+#[derive(Clone, Debug)]
+pub(crate) struct Parser {
+    max_control_line: usize,
+    state: ParserState,
+}
+
+impl Parser {
+    pub(crate) fn new(max_control_line: usize) -> Parser {
+        Parser {
+            max_control_line,
+            state: ParserState::ControlLine { scanned: 0 },
+        }
+    }
+
+    // Returns the frame at the start of `buf`, or `None` if more data is
+    // needed. The parser is ready for the next frame after an error.
+    pub(crate) fn parse<'b>(
+        &mut self,
+        buf: &'b [u8],
+        strict: bool,
+    ) -> Result<Option<Frame<'b>>, NatsError> {
+        let res = self.parse_frame(buf, strict);
+        if !matches!(res, Ok(None)) {
+            self.state = ParserState::ControlLine { scanned: 0 };
+        }
+        res
+    }
+
+    // Length of the frame being parsed, once its control line was received
+    pub(crate) fn frame_len(&self) -> Option<usize> {
+        match self.state {
+            ParserState::Payload { frame_len } => Some(frame_len),
+            ParserState::ControlLine { .. } => None,
+        }
+    }
+
+    fn parse_frame<'b>(
+        &mut self,
+        buf: &'b [u8],
+        strict: bool,
+    ) -> Result<Option<Frame<'b>>, NatsError> {
+        if let ParserState::Payload { frame_len } = self.state {
+            if buf.len() < frame_len {
+                return Ok(None);
+            }
+        }
+        let scanned = match self.state {
+            ParserState::ControlLine { scanned } => scanned,
+            ParserState::Payload { .. } => 0,
+        };
+        let control_len = match buf[scanned..].iter().position(|&c| c == b'\n') {
+            Some(i) => scanned + i + 1,
+            None => buf.len(),
+        };
+        if control_len > self.max_control_line {
+            return Err(NatsError::from((
+                ErrorKind::ServerProtocolError,
+                "Control line too long",
+                format!(
+                    "Maximum control line length is {} bytes",
+                    self.max_control_line
+                ),
+            )));
+        }
+        if !buf[..control_len].ends_with(b"\n") {
+            self.state = ParserState::ControlLine { scanned: buf.len() };
+            return Ok(None);
+        }
+        let op = parse_control_line(&buf[..control_len], strict)?;
+        let args = match op {
+            ServerOp::Msg(args) => args,
+            ServerOp::Unknown(line) => {
+                return Err(NatsError::from((
+                    ErrorKind::ServerProtocolError,
+                    "Received unexpected response from the server",
+                    line.to_owned(),
+                )))
+            }
+            op => {
+                return Ok(Some(Frame {
+                    op,
+                    headers: None,
+                    payload: &[],
+                    len: control_len,
+                }))
+            }
+        };
+        let frame_len = control_len + args.len + 2;
+        if buf.len() < frame_len {
+            self.state = ParserState::Payload { frame_len };
+            return Ok(None);
+        }
+        if &buf[frame_len - 2..frame_len] != b"\r\n" {
+            return Err(NatsError::from((
+                ErrorKind::ServerProtocolError,
+                "Missing CRLF after a message",
+                args.subject.to_owned(),
+            )));
+        }
+        let msg = &buf[control_len..frame_len - 2];
+        let (headers, payload) = match args.headers_len {
+            None => (None, msg),
+            Some(headers_len) => (Some(&msg[..headers_len]), &msg[headers_len..]),
+        };
+        Ok(Some(Frame {
+            op,
+            headers,
+            payload,
+            len: frame_len,
+        }))
+    }
+}
+
+#[test]
+fn protocol_parser_test() {
+    let input = b"INFO {\"max_payload\":1024}\r\nPING\r\nMSG a.b 7 _INBOX.x 5\r\nhello\r\n\
+                  HMSG c 8 12 14\r\nNATS/1.0\r\n\r\nhi\r\n+OK\r\n-ERR 'Unknown Protocol Operation'\r\n";
+    // Fed one byte at a time, each frame must only be returned once complete
+    let mut parser = Parser::new(4096);
+    let mut frames = Vec::new();
+    let (mut start, mut end) = (0, 0);
+    while end < input.len() {
+        end += 1;
+        if let Some(frame) = parser.parse(&input[start..end], true).unwrap() {
+            start += frame.len;
+            frames.push(frame);
+        }
+    }
+    assert_eq!(start, input.len());
+    assert_eq!(frames.len(), 6);
+    assert_eq!(frames[0].op, ServerOp::Info("{\"max_payload\":1024}\r\n"));
+    assert_eq!(frames[1].op, ServerOp::Ping);
+    assert_eq!(
+        frames[2].op,
+        ServerOp::Msg(MsgArgs {
+            subject: "a.b",
+            sid: 7,
+            reply: Some("_INBOX.x"),
+            headers_len: None,
+            len: 5,
+        })
+    );
+    assert_eq!(frames[2].payload, b"hello");
+    assert_eq!(frames[3].headers, Some(&b"NATS/1.0\r\n\r\n"[..]));
+    assert_eq!(frames[3].payload, b"hi");
+    assert_eq!(frames[4].op, ServerOp::Ok);
+    assert!(matches!(frames[5].op, ServerOp::Err(_)));
+
+    let kind = |input: &[u8], strict: bool| {
+        Parser::new(64)
+            .parse(input, strict)
+            .map(|frame| frame.map(|frame| frame.len))
+            .map_err(|e| e.to_string())
+    };
+    let malformed: &[(&[u8], &str)] = &[
+        (b"MSG a 1\r\n", "Incomplete server response"),
+        (b"MSG a b c 1\r\nx\r\n", "Invalid subscription identifier"),
+        (b"MSG a 1 b c 1\r\nx\r\n", "Too many arguments in MSG"),
+        (b"MSG a 1 -1\r\nx\r\n", "Suspicous message length"),
+        (
+            b"MSG a 1 99999999999999999999999\r\n",
+            "Suspicous message length",
+        ),
+        (
+            b"MSG a 1 18446744073709551615\r\n",
+            "Suspicous message length",
+        ),
+        (b"MSG a 1 67108865\r\n", "Suspicous message length"),
+        (
+            b"HMSG a 1 5 2\r\nhello\r\n",
+            "Headers longer than the message",
+        ),
+        (b"MSG a 1 1\r\nxy\r\n", "Missing CRLF after a message"),
+        (b"PING\n", "Control line not terminated by CRLF"),
+        (b"PI\r\n", "Incomplete server response"),
+        (
+            b"NOPE NOPE\r\n",
+            "Received unexpected response from the server",
+        ),
+        (b"INFO \xff\r\n", "Invalid UTF-8"),
+    ];
+    for &(input, errmsg) in malformed {
+        let e = kind(input, true).unwrap_err();
+        assert!(e.contains(errmsg), "{:?}: {}", input, e);
+    }
+    let e = kind(&[b'x'; 65], true).unwrap_err();
+    assert!(e.contains("Control line too long"), "{}", e);
+    // Without strict mode, a sloppy server is tolerated
+    assert_eq!(kind(b"PING\n", false), Ok(Some(5)));
+    assert_eq!(kind(b"MSG a b 1\r\nx\r\n", false), Ok(Some(14)));
+    assert_eq!(kind(b"MSG a 1 1\r\nx", true), Ok(None));
+    // Larger than what a server may announce, but below the sanity bound
+    assert_eq!(kind(b"MSG a 1 67108864\r\n", true), Ok(None));
+}
@@ -0,0 +1,221 @@
+use rand::{thread_rng, Rng};
+use std::{collections::VecDeque, fmt, time::Duration};
+
+const DEFAULT_MAX_ATTEMPTS: u32 = 10;
+const DEFAULT_DELAY_MS: u64 = 250;
+const DEFAULT_ROUNDS_BEFORE_BREAKING: u32 = 4;
+const DEFAULT_WAIT_AFTER_BREAKING_MS: u64 = 2000;
+// Longest delay between connection rounds, whatever the policy
+const MAX_DELAY_S: f64 = 86400.0;
+
+// How operations are retried after a connection failure.
+// `max_attempts` bounds the number of times an operation is retried, `None`
+// retrying forever. Each attempt reconnects with up to
+// `CircuitBreakerPolicy::rounds_before_breaking` connection rounds, which
+// `max_attempts` doesn't limit. The delay between connection rounds starts at
+// `base_delay`, is multiplied by `multiplier` after each failed round up to
+// `max_delay`, and is randomized by +/- `jitter` (between 0 and 1).
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ReconnectPolicy {
+    pub max_attempts: Option<u32>,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub multiplier: f64,
+    pub jitter: f64,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> ReconnectPolicy {
+        ReconnectPolicy {
+            max_attempts: Some(DEFAULT_MAX_ATTEMPTS),
+            base_delay: Duration::from_millis(DEFAULT_DELAY_MS),
+            max_delay: Duration::from_millis(DEFAULT_DELAY_MS),
+            multiplier: 1.0,
+            jitter: 0.0,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    // Delay before the next connection round, after `failures` failed rounds
+    pub fn delay(&self, failures: u32) -> Duration {
+        let base = self.base_delay.as_secs_f64();
+        let max = self.max_delay.as_secs_f64().max(base);
+        let exp = self
+            .multiplier
+            .max(1.0)
+            .powi(failures.min(i32::MAX as u32) as i32);
+        let delay = (base * exp).min(max);
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        let delay = if jitter > 0.0 {
+            delay * thread_rng().gen_range(1.0 - jitter, 1.0 + jitter)
+        } else {
+            delay
+        };
+        // `from_secs_f64()` panics on values that don't fit in a `Duration`
+        if delay.is_nan() {
+            return Duration::from_secs(0);
+        }
+        Duration::from_secs_f64(delay.clamp(0.0, MAX_DELAY_S))
+    }
+
+    pub(crate) fn allows_attempt(&self, attempt: u32) -> bool {
+        self.max_attempts.is_none_or(|max| attempt < max)
+    }
+}
+
+// After `rounds_before_breaking` rounds of failed connection attempts to every
+// server, the circuit breaker opens, and connections fail immediately for
+// `wait_after_breaking`. The delay between rounds is set by the
+// `ReconnectPolicy`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CircuitBreakerPolicy {
+    pub rounds_before_breaking: u32,
+    pub wait_after_breaking: Duration,
+}
+
+impl Default for CircuitBreakerPolicy {
+    fn default() -> CircuitBreakerPolicy {
+        CircuitBreakerPolicy {
+            rounds_before_breaking: DEFAULT_ROUNDS_BEFORE_BREAKING,
+            wait_after_breaking: Duration::from_millis(DEFAULT_WAIT_AFTER_BREAKING_MS),
+        }
+    }
+}
+
+// What to do with a message published while disconnected if the reconnect
+// buffer is full. `Block` keeps trying to reconnect, so that the buffer can
+// be flushed, for up to the given duration before failing with a `Timeout`
+// error.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ReconnectBufferOverflow {
+    #[default]
+    Error,
+    DropOldest,
+    DropNewest,
+    Block(Duration),
+}
+
+// Called with the subject and the size of every message discarded from the
+// reconnect buffer
+pub trait DiscardCallback: Send {
+    fn call(&mut self, subject: &str, size: usize);
+}
+
+impl<F: FnMut(&str, usize) + Send> DiscardCallback for F {
+    fn call(&mut self, subject: &str, size: usize) {
+        self(subject, size)
+    }
+}
+
+impl fmt::Debug for dyn DiscardCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "DiscardCallback {{}}")
+    }
+}
+
+// PUB commands queued while the client is disconnected, with their subject,
+// up to `max_size` bytes, and sent once the connection is re-established
+#[derive(Debug)]
+pub(crate) struct ReconnectBuffer {
+    commands: VecDeque<(String, Vec<u8>)>,
+    size: usize,
+    max_size: usize,
+}
+
+impl ReconnectBuffer {
+    pub(crate) fn new(max_size: usize) -> ReconnectBuffer {
+        ReconnectBuffer {
+            commands: VecDeque::new(),
+            size: 0,
+            max_size,
+        }
+    }
+
+    pub(crate) fn fits(&self, len: usize) -> bool {
+        self.size + len <= self.max_size
+    }
+
+    pub(crate) fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    pub(crate) fn push(&mut self, subject: &str, cmd: Vec<u8>) {
+        self.size += cmd.len();
+        self.commands.push_back((subject.to_owned(), cmd));
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<(String, Vec<u8>)> {
+        let (subject, cmd) = self.commands.pop_front()?;
+        self.size -= cmd.len();
+        Some((subject, cmd))
+    }
+
+    // Puts back a command that couldn't be sent, ahead of the others
+    pub(crate) fn unpop(&mut self, subject: String, cmd: Vec<u8>) {
+        self.size += cmd.len();
+        self.commands.push_front((subject, cmd));
+    }
+
+    pub(crate) fn size(&self) -> usize {
+        self.size
+    }
+}
+
+#[test]
+fn reconnect_policy_test() {
+    let policy = ReconnectPolicy {
+        max_attempts: None,
+        base_delay: Duration::from_millis(100),
+        max_delay: Duration::from_secs(1),
+        multiplier: 2.0,
+        jitter: 0.0,
+    };
+    assert_eq!(policy.delay(0), Duration::from_millis(100));
+    assert_eq!(policy.delay(2), Duration::from_millis(400));
+    assert_eq!(policy.delay(10), Duration::from_secs(1));
+    assert!(policy.allows_attempt(u32::MAX - 1));
+
+    let policy = ReconnectPolicy {
+        jitter: 0.5,
+        ..policy
+    };
+    for _ in 0..100 {
+        let delay = policy.delay(0);
+        assert!(delay >= Duration::from_millis(50) && delay <= Duration::from_millis(150));
+    }
+    assert!(!ReconnectPolicy::default().allows_attempt(10));
+
+    let policy = ReconnectPolicy {
+        max_delay: Duration::MAX,
+        multiplier: f64::INFINITY,
+        ..policy
+    };
+    assert!(policy.delay(0) <= Duration::from_millis(150));
+    assert_eq!(policy.delay(1), Duration::from_secs(86400));
+    let policy = ReconnectPolicy {
+        base_delay: Duration::from_secs(0),
+        multiplier: f64::NAN,
+        jitter: f64::NAN,
+        ..policy
+    };
+    assert_eq!(policy.delay(3), Duration::from_secs(0));
+}
+
+#[test]
+fn reconnect_buffer_test() {
+    let mut buffer = ReconnectBuffer::new(10);
+    assert!(buffer.fits(7));
+    buffer.push("a", b"PUB a 1".to_vec());
+    assert!(!buffer.fits(7));
+    assert!(buffer.fits(3));
+    buffer.push("b", b"PUB".to_vec());
+    assert_eq!(buffer.size(), 10);
+    let (subject, cmd) = buffer.pop().unwrap();
+    assert_eq!(subject, "a");
+    buffer.unpop(subject, cmd);
+    assert_eq!(buffer.size(), 10);
+    assert_eq!(buffer.pop().unwrap().1, b"PUB a 1");
+    assert_eq!(buffer.pop().unwrap().1, b"PUB");
+    assert!(buffer.pop().is_none());
+}
@@ -0,0 +1,108 @@
+use crate::client::Event;
+use crate::errors::*;
+use crate::headers::Headers;
+use bytes::Bytes;
+use serde::de::DeserializeOwned;
+use std::time::SystemTime;
+
+// Status codes sent by the server in the headers of a reply, instead of a
+// reply from a responder
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum StatusCode {
+    IdleHeartbeat,
+    NotFound,
+    RequestTimeout,
+    NoResponders,
+    Other(u16),
+}
+
+impl StatusCode {
+    pub fn from_code(code: u16) -> StatusCode {
+        match code {
+            100 => StatusCode::IdleHeartbeat,
+            404 => StatusCode::NotFound,
+            408 => StatusCode::RequestTimeout,
+            503 => StatusCode::NoResponders,
+            code => StatusCode::Other(code),
+        }
+    }
+
+    pub fn code(self) -> u16 {
+        match self {
+            StatusCode::IdleHeartbeat => 100,
+            StatusCode::NotFound => 404,
+            StatusCode::RequestTimeout => 408,
+            StatusCode::NoResponders => 503,
+            StatusCode::Other(code) => code,
+        }
+    }
+}
+
+// Reply to a request
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Reply {
+    // Inbox the reply was sent to
+    pub subject: String,
+    pub msg: Bytes,
+    pub headers: Option<Headers>,
+    pub status: Option<StatusCode>,
+    // Description following the status code, such as "No Messages"
+    pub description: Option<String>,
+    pub received_at: SystemTime,
+}
+
+impl Reply {
+    pub fn decode_json<T: DeserializeOwned>(&self) -> Result<T, NatsError> {
+        crate::json::decode_json(&self.msg)
+    }
+}
+
+impl From<Event> for Reply {
+    fn from(event: Event) -> Reply {
+        let status = event
+            .headers
+            .as_ref()
+            .and_then(|headers| headers.status)
+            .map(StatusCode::from_code);
+        let description = event
+            .headers
+            .as_ref()
+            .and_then(|headers| headers.description.clone());
+        Reply {
+            subject: event.subject,
+            msg: event.msg,
+            headers: event.headers,
+            status,
+            description,
+            received_at: event.received_at,
+        }
+    }
+}
+
+#[test]
+fn reply_status_test() {
+    use crate::client::Channel;
+
+    let event = Event {
+        subject: "_INBOX.x.1".to_owned(),
+        channel: Channel { sid: 1 },
+        msg: Bytes::new(),
+        inbox: None,
+        headers: Some(Headers::decode(b"NATS/1.0 408 Request Timeout\r\n\r\n").unwrap()),
+        received_at: SystemTime::now(),
+    };
+    let reply = Reply::from(event.clone());
+    assert_eq!(reply.status, Some(StatusCode::RequestTimeout));
+    assert_eq!(reply.description.as_deref(), Some("Request Timeout"));
+    assert_eq!(StatusCode::from_code(100), StatusCode::IdleHeartbeat);
+    assert_eq!(StatusCode::from_code(409).code(), 409);
+
+    let reply = Reply::from(Event {
+        headers: None,
+        msg: Bytes::from_static(b"{\"ok\":true}"),
+        ..event
+    });
+    assert_eq!(reply.status, None);
+    let value: serde_json::Value = reply.decode_json().unwrap();
+    assert_eq!(value["ok"], true);
+}
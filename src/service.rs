@@ -0,0 +1,434 @@
+use crate::client::{Channel, Client, Event};
+use crate::errors::*;
+use crate::headers::Headers;
+use crate::nuid::{IdGenerator, Nuid};
+use bytes::Bytes;
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    fmt,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+const DEFAULT_QUEUE_GROUP: &str = "q";
+const SERVICE_ERROR_HEADER: &str = "Nats-Service-Error";
+const SERVICE_ERROR_CODE_HEADER: &str = "Nats-Service-Error-Code";
+const PING_RESPONSE_TYPE: &str = "io.nats.micro.v1.ping_response";
+const INFO_RESPONSE_TYPE: &str = "io.nats.micro.v1.info_response";
+const STATS_RESPONSE_TYPE: &str = "io.nats.micro.v1.stats_response";
+
+#[derive(Clone, Debug)]
+pub struct ServiceConfig {
+    pub name: String,
+    pub version: String,
+    pub description: Option<String>,
+    pub metadata: HashMap<String, String>,
+    // Queue group the endpoints subscribe with, so that requests are spread
+    // over the instances of the service
+    pub queue_group: String,
+}
+
+impl ServiceConfig {
+    pub fn new(name: &str, version: &str) -> ServiceConfig {
+        ServiceConfig {
+            name: name.to_owned(),
+            version: version.to_owned(),
+            description: None,
+            metadata: HashMap::new(),
+            queue_group: DEFAULT_QUEUE_GROUP.to_owned(),
+        }
+    }
+}
+
+// Error returned by an endpoint handler. It is sent back to the requester in
+// the `Nats-Service-Error` and `Nats-Service-Error-Code` headers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServiceError {
+    pub code: u16,
+    pub description: String,
+}
+
+impl ServiceError {
+    pub fn new(code: u16, description: &str) -> ServiceError {
+        ServiceError {
+            code,
+            description: description.to_owned(),
+        }
+    }
+}
+
+pub trait EndpointHandler: Send {
+    fn call(&mut self, request: &Event) -> Result<Bytes, ServiceError>;
+}
+
+impl<F: FnMut(&Event) -> Result<Bytes, ServiceError> + Send> EndpointHandler for F {
+    fn call(&mut self, request: &Event) -> Result<Bytes, ServiceError> {
+        self(request)
+    }
+}
+
+impl fmt::Debug for dyn EndpointHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "EndpointHandler {{}}")
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EndpointStats {
+    pub name: String,
+    pub subject: String,
+    pub queue_group: String,
+    pub num_requests: u64,
+    pub num_errors: u64,
+    pub last_error: Option<String>,
+    pub processing_time: Duration,
+}
+
+impl EndpointStats {
+    pub fn average_processing_time(&self) -> Duration {
+        match self.num_requests {
+            0 => Duration::from_secs(0),
+            n => Duration::from_nanos((self.processing_time.as_nanos() / u128::from(n)) as u64),
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "name": self.name,
+            "subject": self.subject,
+            "queue_group": self.queue_group,
+            "num_requests": self.num_requests,
+            "num_errors": self.num_errors,
+            "last_error": self.last_error.as_deref().unwrap_or(""),
+            "processing_time": self.processing_time.as_nanos() as u64,
+            "average_processing_time": self.average_processing_time().as_nanos() as u64,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Endpoint {
+    channel: Channel,
+    stats: EndpointStats,
+    handler: Box<dyn EndpointHandler>,
+}
+
+#[derive(Copy, Clone, Debug)]
+enum Verb {
+    Ping,
+    Info,
+    Stats,
+}
+
+// Service following the NATS services protocol. Besides its endpoints, the
+// service subscribes to `$SRV.PING`, `$SRV.INFO` and `$SRV.STATS`, optionally
+// followed by its name and identifier, so that it can be discovered and
+// monitored. Events must be passed to `handle()`, or `run()` can be used to
+// dedicate the client to the service.
+#[derive(Debug)]
+pub struct Service {
+    id: String,
+    config: ServiceConfig,
+    started: SystemTime,
+    control: HashMap<u64, Verb>,
+    endpoints: Vec<Endpoint>,
+}
+
+impl Service {
+    pub fn add(client: &mut Client, config: ServiceConfig) -> Result<Service, NatsError> {
+        name_check(&config.name, "Invalid service name")?;
+        if config.version.is_empty() {
+            return Err(NatsError::from((
+                ErrorKind::InvalidClientConfig,
+                "A service version is required",
+            )));
+        }
+        let id = Nuid::new().next_id();
+        let mut service = Service {
+            id,
+            config,
+            started: SystemTime::now(),
+            control: HashMap::new(),
+            endpoints: Vec::new(),
+        };
+        for &(verb, name) in &[
+            (Verb::Ping, "PING"),
+            (Verb::Info, "INFO"),
+            (Verb::Stats, "STATS"),
+        ] {
+            for subject in &[
+                format!("$SRV.{}", name),
+                format!("$SRV.{}.{}", name, service.config.name),
+                format!("$SRV.{}.{}.{}", name, service.config.name, service.id),
+            ] {
+                let channel = match client.subscribe(subject, None) {
+                    Ok(channel) => channel,
+                    Err(e) => {
+                        let _ = service.stop(client);
+                        return Err(e);
+                    }
+                };
+                service.control.insert(channel.sid, verb);
+            }
+        }
+        Ok(service)
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn config(&self) -> &ServiceConfig {
+        &self.config
+    }
+
+    // Handles requests sent to `subject` with `handler`, the name being the
+    // one reported by INFO and STATS
+    pub fn add_endpoint<H>(
+        &mut self,
+        client: &mut Client,
+        name: &str,
+        subject: &str,
+        handler: H,
+    ) -> Result<(), NatsError>
+    where
+        H: EndpointHandler + 'static,
+    {
+        name_check(name, "Invalid endpoint name")?;
+        if self.endpoints.iter().any(|e| e.stats.name == name) {
+            return Err(NatsError::from((
+                ErrorKind::InvalidClientConfig,
+                "Duplicate endpoint name",
+                name.to_owned(),
+            )));
+        }
+        let queue_group = self.config.queue_group.clone();
+        let channel = client.subscribe(subject, Some(&queue_group))?;
+        self.endpoints.push(Endpoint {
+            channel,
+            stats: EndpointStats {
+                name: name.to_owned(),
+                subject: subject.to_owned(),
+                queue_group,
+                ..Default::default()
+            },
+            handler: Box::new(handler),
+        });
+        Ok(())
+    }
+
+    // Answers the event if it was delivered to one of the subscriptions of the
+    // service. Returns `false` if the event belongs to another subscription.
+    pub fn handle(&mut self, client: &mut Client, event: &Event) -> Result<bool, NatsError> {
+        if let Some(&verb) = self.control.get(&event.channel.sid) {
+            let response = match verb {
+                Verb::Ping => self.ping_response(),
+                Verb::Info => self.info_response(),
+                Verb::Stats => self.stats_response(),
+            };
+            if event.inbox.is_some() {
+                event.respond(client, response.to_string().as_bytes())?;
+            }
+            return Ok(true);
+        }
+        let endpoint = match self
+            .endpoints
+            .iter_mut()
+            .find(|endpoint| endpoint.channel.sid == event.channel.sid)
+        {
+            None => return Ok(false),
+            Some(endpoint) => endpoint,
+        };
+        let started = Instant::now();
+        let result = endpoint.handler.call(event);
+        endpoint.stats.num_requests += 1;
+        endpoint.stats.processing_time += started.elapsed();
+        if let Err(ref e) = result {
+            endpoint.stats.num_errors += 1;
+            endpoint.stats.last_error = Some(format!("{}:{}", e.code, e.description));
+        }
+        let inbox = match event.inbox {
+            None => return Ok(true),
+            Some(ref inbox) => inbox,
+        };
+        match result {
+            Ok(reply) => client.publish(inbox, &reply)?,
+            Err(e) => {
+                let mut headers = Headers::new();
+                headers
+                    .insert(SERVICE_ERROR_HEADER, &e.description)
+                    .insert(SERVICE_ERROR_CODE_HEADER, &e.code.to_string());
+                client.publish_with_headers(inbox, &headers, b"")?;
+            }
+        }
+        Ok(true)
+    }
+
+    // Handles events until an error occurs. Events that don't belong to the
+    // service are dropped.
+    pub fn run(&mut self, client: &mut Client) -> Result<(), NatsError> {
+        loop {
+            let event = client.wait()?;
+            self.handle(client, &event)?;
+        }
+    }
+
+    pub fn stats(&self) -> Vec<EndpointStats> {
+        self.endpoints
+            .iter()
+            .map(|endpoint| endpoint.stats.clone())
+            .collect()
+    }
+
+    pub fn reset_stats(&mut self) {
+        for endpoint in &mut self.endpoints {
+            let stats = &mut endpoint.stats;
+            stats.num_requests = 0;
+            stats.num_errors = 0;
+            stats.last_error = None;
+            stats.processing_time = Duration::from_secs(0);
+        }
+    }
+
+    // Unsubscribes from the endpoints and from the discovery subjects
+    pub fn stop(mut self, client: &mut Client) -> Result<(), NatsError> {
+        let sids = self.control.drain().map(|(sid, _)| sid).chain(
+            self.endpoints
+                .drain(..)
+                .map(|endpoint| endpoint.channel.sid),
+        );
+        let mut res = Ok(());
+        for sid in sids {
+            if let Err(e) = client.unsubscribe(Channel { sid }) {
+                res = Err(e);
+            }
+        }
+        res
+    }
+
+    fn ping_response(&self) -> Value {
+        json!({
+            "type": PING_RESPONSE_TYPE,
+            "name": self.config.name,
+            "id": self.id,
+            "version": self.config.version,
+            "metadata": self.config.metadata,
+        })
+    }
+
+    fn info_response(&self) -> Value {
+        let endpoints: Vec<Value> = self
+            .endpoints
+            .iter()
+            .map(|endpoint| {
+                json!({
+                    "name": endpoint.stats.name,
+                    "subject": endpoint.stats.subject,
+                    "queue_group": endpoint.stats.queue_group,
+                })
+            })
+            .collect();
+        json!({
+            "type": INFO_RESPONSE_TYPE,
+            "name": self.config.name,
+            "id": self.id,
+            "version": self.config.version,
+            "description": self.config.description.as_deref().unwrap_or(""),
+            "metadata": self.config.metadata,
+            "endpoints": endpoints,
+        })
+    }
+
+    fn stats_response(&self) -> Value {
+        let endpoints: Vec<Value> = self
+            .endpoints
+            .iter()
+            .map(|endpoint| endpoint.stats.to_json())
+            .collect();
+        json!({
+            "type": STATS_RESPONSE_TYPE,
+            "name": self.config.name,
+            "id": self.id,
+            "version": self.config.version,
+            "metadata": self.config.metadata,
+            "started": rfc3339(self.started),
+            "endpoints": endpoints,
+        })
+    }
+}
+
+// Service and endpoint names are restricted to alphanumeric characters,
+// dashes and underscores, since they are used as subject tokens
+fn name_check(name: &str, errmsg: &'static str) -> Result<(), NatsError> {
+    if name.is_empty()
+        || !name
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || c == b'-' || c == b'_')
+    {
+        return Err(NatsError::from((
+            ErrorKind::InvalidClientConfig,
+            errmsg,
+            name.to_owned(),
+        )));
+    }
+    Ok(())
+}
+
+// UTC timestamp with millisecond precision, e.g. 2020-09-13T12:26:40.000Z
+fn rfc3339(time: SystemTime) -> String {
+    let elapsed = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = elapsed.as_secs();
+    let (days, rem) = (secs / 86400, secs % 86400);
+    // Civil date from the number of days since 1970-01-01
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        elapsed.subsec_millis()
+    )
+}
+
+#[test]
+fn service_test() {
+    assert_eq!(
+        rfc3339(UNIX_EPOCH + Duration::from_millis(1_600_000_000_250)),
+        "2020-09-13T12:26:40.250Z"
+    );
+    assert_eq!(rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+    assert!(name_check("billing-v2_eu", "Invalid service name").is_ok());
+    assert!(name_check("billing.eu", "Invalid service name").is_err());
+    assert!(name_check("", "Invalid service name").is_err());
+
+    let stats = EndpointStats {
+        name: "add".to_owned(),
+        num_requests: 4,
+        num_errors: 1,
+        last_error: Some("400:bad input".to_owned()),
+        processing_time: Duration::from_millis(10),
+        ..Default::default()
+    };
+    assert_eq!(stats.average_processing_time(), Duration::from_micros(2500));
+    let json = stats.to_json();
+    assert_eq!(json["average_processing_time"], 2_500_000);
+    assert_eq!(json["last_error"], "400:bad input");
+
+    let stats = EndpointStats {
+        num_requests: (1 << 32) + 1,
+        processing_time: Duration::from_secs((1 << 32) + 1),
+        ..Default::default()
+    };
+    assert_eq!(stats.average_processing_time(), Duration::from_secs(1));
+}
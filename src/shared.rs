@@ -0,0 +1,1066 @@
+use crate::client::{no_responders_check, Channel, Client, Event, Subscription};
+use crate::errors::*;
+use crate::executor::WorkerPool;
+use crate::headers::Headers;
+use crate::reply::Reply;
+use crate::stream;
+use log::warn;
+use std::{
+    cmp,
+    collections::HashMap,
+    fmt, iter,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{
+            self, Receiver, RecvError, RecvTimeoutError, Sender, SyncSender, TryRecvError,
+            TrySendError,
+        },
+        Arc, Condvar, Mutex, MutexGuard,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+const READER_POLL_INTERVAL_MS: u64 = 100;
+const SHUTDOWN_POLL_INTERVAL_MS: u64 = 5;
+const CHANNEL_POLL_INTERVAL_MS: u64 = 5;
+const DEFAULT_MAX_PENDING_MESSAGES: usize = 65536;
+const DEFAULT_MAX_PENDING_BYTES: usize = 64 * 1024 * 1024;
+
+// What the reader thread does when a subscription exceeds its pending limits
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SlowConsumerPolicy {
+    // Drop new messages until the receiver catches up
+    Drop,
+    // Wait for the receiver, delaying the delivery to every other
+    // subscription
+    Block,
+}
+
+// Maximum number of messages and bytes received for a subscription, but not
+// consumed from its receiver yet
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PendingLimits {
+    pub max_messages: usize,
+    pub max_bytes: usize,
+    pub policy: SlowConsumerPolicy,
+}
+
+impl Default for PendingLimits {
+    fn default() -> PendingLimits {
+        PendingLimits {
+            max_messages: DEFAULT_MAX_PENDING_MESSAGES,
+            max_bytes: DEFAULT_MAX_PENDING_BYTES,
+            policy: SlowConsumerPolicy::Drop,
+        }
+    }
+}
+
+// Channel to which the reader thread pushes the events of a subscription
+// created with `subscribe_to_channel()`. Implemented for the senders of
+// bounded standard channels, and of crossbeam and flume channels with the
+// `crossbeam-channel` and `flume` features.
+pub trait EventSender: Send + Sync {
+    // Same as the `try_send()` method of standard channels
+    #[allow(clippy::result_large_err)]
+    fn try_send(&self, event: Event) -> Result<(), TrySendError<Event>>;
+}
+
+impl EventSender for SyncSender<Event> {
+    fn try_send(&self, event: Event) -> Result<(), TrySendError<Event>> {
+        SyncSender::try_send(self, event)
+    }
+}
+
+#[cfg(feature = "crossbeam-channel")]
+impl EventSender for crossbeam_channel::Sender<Event> {
+    fn try_send(&self, event: Event) -> Result<(), TrySendError<Event>> {
+        crossbeam_channel::Sender::try_send(self, event).map_err(|e| match e {
+            crossbeam_channel::TrySendError::Full(event) => TrySendError::Full(event),
+            crossbeam_channel::TrySendError::Disconnected(event) => {
+                TrySendError::Disconnected(event)
+            }
+        })
+    }
+}
+
+#[cfg(feature = "flume")]
+impl EventSender for flume::Sender<Event> {
+    fn try_send(&self, event: Event) -> Result<(), TrySendError<Event>> {
+        flume::Sender::try_send(self, event).map_err(|e| match e {
+            flume::TrySendError::Full(event) => TrySendError::Full(event),
+            flume::TrySendError::Disconnected(event) => TrySendError::Disconnected(event),
+        })
+    }
+}
+
+impl fmt::Debug for dyn EventSender {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "EventSender {{}}")
+    }
+}
+
+// Clonable, thread-safe handle to a client.
+// Operations are serialized by a single lock on the client, so publishers
+// wait for each other. A thread waiting for events doesn't hold that lock
+// while the connection is idle, so that it doesn't delay publishers, but
+// they still wait while received events are parsed, and TLS records are
+// decoded.
+#[derive(Clone, Debug)]
+pub struct SharedClient {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    client: Mutex<Client>,
+    // Set while a thread waits for data from the server. Other readers wait
+    // for the condition variable, notified when new events are available.
+    reading: Mutex<bool>,
+    events_available: Condvar,
+    // Subscriptions whose events are forwarded by the reader thread
+    routes: Mutex<HashMap<u64, Route>>,
+    // Reader threads started by this client, stopped by `stop_readers()`
+    threads: Mutex<Vec<BackgroundThread>>,
+    thread_seq: AtomicU64,
+}
+
+#[derive(Debug)]
+struct BackgroundThread {
+    name: String,
+    stop: Arc<AtomicBool>,
+    handle: JoinHandle<()>,
+}
+
+#[derive(Clone, Debug)]
+struct Route {
+    sink: Sink,
+    pending: Arc<Pending>,
+}
+
+#[derive(Clone, Debug)]
+enum Sink {
+    Receiver(Sender<Event>),
+    // The channel's capacity replaces the pending limits
+    Channel(Arc<dyn EventSender>),
+}
+
+#[derive(Debug, Default)]
+struct Pending {
+    state: Mutex<PendingState>,
+    consumed: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct PendingState {
+    limits: PendingLimits,
+    messages: usize,
+    bytes: usize,
+    dropped: u64,
+    // Set once the limits were exceeded, until a message can be queued again
+    slow: bool,
+    receiver_dropped: bool,
+}
+
+impl SharedClient {
+    pub fn new(client: Client) -> SharedClient {
+        SharedClient {
+            inner: Arc::new(Inner {
+                client: Mutex::new(client),
+                reading: Mutex::new(false),
+                events_available: Condvar::new(),
+                routes: Mutex::new(HashMap::new()),
+                threads: Mutex::new(Vec::new()),
+                thread_seq: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    // Exclusive access to the client, for operations not exposed here
+    pub fn lock(&self) -> MutexGuard<'_, Client> {
+        self.inner.client.lock().unwrap()
+    }
+
+    pub fn publish(&self, subject: &str, msg: &[u8]) -> Result<(), NatsError> {
+        self.lock().publish(subject, msg)
+    }
+
+    pub fn try_publish(&self, subject: &str, msg: &[u8]) -> Result<(), NatsError> {
+        self.lock().try_publish(subject, msg)
+    }
+
+    pub fn publish_with_inbox(
+        &self,
+        subject: &str,
+        msg: &[u8],
+        inbox: &str,
+    ) -> Result<(), NatsError> {
+        self.lock().publish_with_inbox(subject, msg, inbox)
+    }
+
+    pub fn publish_with_headers(
+        &self,
+        subject: &str,
+        headers: &Headers,
+        msg: &[u8],
+    ) -> Result<(), NatsError> {
+        self.lock().publish_with_headers(subject, headers, msg)
+    }
+
+    pub fn subscribe(&self, subject: &str, queue: Option<&str>) -> Result<Channel, NatsError> {
+        self.lock().subscribe(subject, queue)
+    }
+
+    pub fn unsubscribe(&self, channel: Channel) -> Result<(), NatsError> {
+        self.inner.routes.lock().unwrap().remove(&channel.sid);
+        self.lock().unsubscribe(channel)
+    }
+
+    // Subscribes, and returns a receiver to which the reader thread forwards
+    // the events of the subscription. The subscription is removed once the
+    // receiver is dropped and another event is received.
+    pub fn subscribe_with_receiver(
+        &self,
+        subject: &str,
+        queue: Option<&str>,
+    ) -> Result<(Channel, EventReceiver), NatsError> {
+        let channel = self.subscribe(subject, queue)?;
+        let (tx, rx) = mpsc::channel();
+        let pending = Arc::new(Pending::default());
+        self.inner.routes.lock().unwrap().insert(
+            channel.sid,
+            Route {
+                sink: Sink::Receiver(tx),
+                pending: pending.clone(),
+            },
+        );
+        Ok((channel, EventReceiver { rx, pending }))
+    }
+
+    // Subscribes, and has the reader thread push the events of the
+    // subscription to a channel provided by the application. When the
+    // channel is full, new messages are dropped, or the reader waits if the
+    // `Block` policy was set with `set_pending_limits()`. The subscription is
+    // removed once the receiving side is dropped and another event is
+    // received.
+    pub fn subscribe_to_channel<S: EventSender + 'static>(
+        &self,
+        subject: &str,
+        queue: Option<&str>,
+        sender: S,
+    ) -> Result<Channel, NatsError> {
+        let channel = self.subscribe(subject, queue)?;
+        self.route_to_channel(channel, Arc::new(sender), SlowConsumerPolicy::Drop);
+        Ok(channel)
+    }
+
+    pub(crate) fn route_to_channel(
+        &self,
+        channel: Channel,
+        sender: Arc<dyn EventSender>,
+        policy: SlowConsumerPolicy,
+    ) {
+        let pending = Pending::default();
+        pending.state.lock().unwrap().limits.policy = policy;
+        self.inner.routes.lock().unwrap().insert(
+            channel.sid,
+            Route {
+                sink: Sink::Channel(sender),
+                pending: Arc::new(pending),
+            },
+        );
+    }
+
+    // Subscribes, and has `handler` called by the threads of `pool` with the
+    // events forwarded by the reader thread. At most `max_in_flight` events
+    // of the subscription are handled concurrently; with 1, they are handled
+    // in order. The subscription is removed once the pool is shut down and
+    // another event is received.
+    pub fn subscribe_with_handler<H>(
+        &self,
+        subject: &str,
+        queue: Option<&str>,
+        pool: &WorkerPool,
+        max_in_flight: usize,
+        handler: H,
+    ) -> Result<Channel, NatsError>
+    where
+        H: Fn(Event) + Send + Sync + 'static,
+    {
+        let channel = self.subscribe(subject, queue)?;
+        let dispatcher = pool.dispatcher(max_in_flight, handler);
+        self.route_to_channel(channel, Arc::new(dispatcher), SlowConsumerPolicy::Drop);
+        Ok(channel)
+    }
+
+    // Limits the events queued for a subscription created with
+    // `subscribe_with_receiver()`. When a limit is exceeded, a `SlowConsumer`
+    // error is reported to the reader's error callback. Only the policy
+    // applies to subscriptions created with `subscribe_to_channel()`.
+    pub fn set_pending_limits(
+        &self,
+        channel: Channel,
+        limits: PendingLimits,
+    ) -> Result<(), NatsError> {
+        let routes = self.inner.routes.lock().unwrap();
+        let route = routes.get(&channel.sid).ok_or((
+            ErrorKind::InvalidClientConfig,
+            "The subscription doesn't have a receiver",
+        ))?;
+        route.pending.state.lock().unwrap().limits = limits;
+        route.pending.consumed.notify_all();
+        Ok(())
+    }
+
+    // Starts a thread reading from the connection, answering server PINGs
+    // even if the application never waits for events, sending PINGs if a
+    // ping interval was set on the client, and forwarding events
+    // to the receivers of `subscribe_with_receiver()`. Other events are kept
+    // for `wait()` and `select()`. Errors are reported to `on_error`.
+    pub fn start_reader<F: ErrorCallback + 'static>(&self, mut on_error: F) -> ReaderHandle {
+        let stop = Arc::new(AtomicBool::new(false));
+        let shared = self.clone();
+        let stop_reader = stop.clone();
+        let seq = self.inner.thread_seq.fetch_add(1, Ordering::Relaxed) + 1;
+        let name = format!("nats-reader-{}", seq);
+        let handle = thread::Builder::new()
+            .name(name.clone())
+            .spawn(move || {
+                let poll_interval = Duration::from_millis(READER_POLL_INTERVAL_MS);
+                while !stop_reader.load(Ordering::Relaxed) {
+                    let channels: Vec<Channel> = shared
+                        .inner
+                        .routes
+                        .lock()
+                        .unwrap()
+                        .keys()
+                        .map(|&sid| Channel { sid })
+                        .collect();
+                    match shared.wait_until(
+                        &|client: &mut Client| client.take_pending_event(Some(&channels)),
+                        Some(Instant::now() + poll_interval),
+                    ) {
+                        Ok(Some(event)) => {
+                            if let Err(e) = shared.route(event, &stop_reader, &mut on_error) {
+                                on_error.call(&e);
+                            }
+                        }
+                        Ok(None) => {}
+                        Err(e) => {
+                            on_error.call(&e);
+                            thread::sleep(poll_interval);
+                        }
+                    }
+                    if let Err(e) = shared.lock().ping_if_idle() {
+                        on_error.call(&e);
+                    }
+                }
+            })
+            .expect("Unable to start the reader thread");
+        self.inner.threads.lock().unwrap().push(BackgroundThread {
+            name: name.clone(),
+            stop: stop.clone(),
+            handle,
+        });
+        ReaderHandle {
+            stop,
+            name,
+            shared: self.clone(),
+        }
+    }
+
+    // Stops the reader threads started by this client, waiting at most
+    // `timeout` for them to exit. Threads still running after the timeout
+    // are named in the error, and can be waited for by calling this function
+    // again, as are threads that panicked. Executors and worker pools are
+    // owned by the application, and stopped by their own `shutdown()`.
+    pub fn stop_readers(&self, timeout: Duration) -> Result<(), NatsError> {
+        let deadline = Instant::now() + timeout;
+        let threads: Vec<BackgroundThread> = self.inner.threads.lock().unwrap().drain(..).collect();
+        for thread in &threads {
+            thread.stop.store(true, Ordering::Relaxed);
+        }
+        let mut running = Vec::new();
+        let mut panicked = Vec::new();
+        for thread in threads {
+            while !thread.handle.is_finished() && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(SHUTDOWN_POLL_INTERVAL_MS));
+            }
+            if !thread.handle.is_finished() {
+                running.push(thread);
+            } else if thread.handle.join().is_err() {
+                panicked.push(thread.name);
+            }
+        }
+        if !running.is_empty() {
+            let names: Vec<&str> = running.iter().map(|thread| thread.name.as_str()).collect();
+            let err = NatsError::from((
+                ErrorKind::Timeout,
+                "Reader threads still running after the timeout",
+                names.join(", "),
+            ));
+            self.inner.threads.lock().unwrap().extend(running);
+            return Err(err);
+        }
+        if !panicked.is_empty() {
+            return Err(NatsError::from((
+                ErrorKind::HandlerPanicked,
+                "Reader threads panicked",
+                panicked.join(", "),
+            )));
+        }
+        Ok(())
+    }
+
+    // Queues an event for its receiver, within the pending limits of the
+    // subscription
+    fn route(
+        &self,
+        event: Event,
+        stop: &AtomicBool,
+        on_error: &mut dyn ErrorCallback,
+    ) -> Result<(), NatsError> {
+        let channel = event.channel;
+        let route = match self.inner.routes.lock().unwrap().get(&channel.sid) {
+            Some(route) => route.clone(),
+            None => return Ok(()),
+        };
+        let tx = match route.sink {
+            Sink::Receiver(tx) => tx,
+            Sink::Channel(sender) => {
+                return self.send_to_channel(event, &*sender, &route.pending, stop, on_error)
+            }
+        };
+        let len = event.msg.len();
+        let receiver_dropped = loop {
+            let mut state = route.pending.state.lock().unwrap();
+            let over_limits = state.messages > 0
+                && (state.messages >= state.limits.max_messages
+                    || state.bytes + len > state.limits.max_bytes);
+            if state.receiver_dropped || !over_limits {
+                state.slow = false;
+                state.messages += 1;
+                state.bytes += len;
+                break state.receiver_dropped;
+            }
+            let drop_event = state.limits.policy == SlowConsumerPolicy::Drop;
+            if drop_event {
+                state.dropped += 1;
+            }
+            if !state.slow {
+                state.slow = true;
+                drop(state);
+                slow_consumer(&event, drop_event, on_error);
+                if drop_event {
+                    return Ok(());
+                }
+                continue;
+            }
+            if drop_event || stop.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+            let poll_interval = Duration::from_millis(READER_POLL_INTERVAL_MS);
+            drop(
+                route
+                    .pending
+                    .consumed
+                    .wait_timeout(state, poll_interval)
+                    .unwrap(),
+            );
+        };
+        if receiver_dropped || tx.send(event).is_err() {
+            self.inner.routes.lock().unwrap().remove(&channel.sid);
+            self.lock().unsubscribe(channel)?;
+        }
+        Ok(())
+    }
+
+    fn send_to_channel(
+        &self,
+        mut event: Event,
+        sender: &dyn EventSender,
+        pending: &Pending,
+        stop: &AtomicBool,
+        on_error: &mut dyn ErrorCallback,
+    ) -> Result<(), NatsError> {
+        let channel = event.channel;
+        loop {
+            event = match sender.try_send(event) {
+                Ok(()) => {
+                    pending.state.lock().unwrap().slow = false;
+                    return Ok(());
+                }
+                Err(TrySendError::Full(event)) => event,
+                Err(TrySendError::Disconnected(_)) => {
+                    self.inner.routes.lock().unwrap().remove(&channel.sid);
+                    return self.lock().unsubscribe(channel);
+                }
+            };
+            let mut state = pending.state.lock().unwrap();
+            let drop_event = state.limits.policy == SlowConsumerPolicy::Drop;
+            if drop_event {
+                state.dropped += 1;
+            }
+            if !state.slow {
+                state.slow = true;
+                drop(state);
+                slow_consumer(&event, drop_event, on_error);
+            }
+            if drop_event || stop.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+            thread::sleep(Duration::from_millis(CHANNEL_POLL_INTERVAL_MS));
+        }
+    }
+
+    pub fn flush(&self) -> Result<(), NatsError> {
+        self.lock().flush()
+    }
+
+    pub fn flush_subscriptions(&self) -> Result<(), NatsError> {
+        self.lock().flush_subscriptions()
+    }
+
+    // Waits for an event delivered to any subscription
+    pub fn wait(&self) -> Result<Event, NatsError> {
+        loop {
+            if let Some(event) =
+                self.wait_until(&|client: &mut Client| client.take_pending_event(None), None)?
+            {
+                return Ok(event);
+            }
+        }
+    }
+
+    // Waits for an event delivered to one of the given channels. Events for
+    // other channels are kept for the threads waiting for them.
+    pub fn select(
+        &self,
+        channels: &[Channel],
+        timeout: Duration,
+    ) -> Result<Option<Event>, NatsError> {
+        if channels.is_empty() {
+            return Ok(None);
+        }
+        self.wait_until(
+            &|client: &mut Client| client.take_pending_event(Some(channels)),
+            Some(Instant::now() + timeout),
+        )
+    }
+
+    pub fn request(
+        &self,
+        subject: &str,
+        msg: &[u8],
+        timeout: Duration,
+    ) -> Result<Reply, NatsError> {
+        let started = Instant::now();
+        let inbox = self.lock().make_request(subject, msg)?;
+        let reply = self.wait_until(
+            &|client: &mut Client| client.take_reply(&inbox),
+            Some(Instant::now() + timeout),
+        )?;
+        match reply {
+            Some(event) => {
+                let client = self.lock();
+                client.record_request_latency(subject, started);
+                no_responders_check(subject, event)
+                    .map(Reply::from)
+                    .map_err(|e| client.label_error(e))
+            }
+            None => {
+                let mut client = self.lock();
+                client.cancel_request(&inbox);
+                Err(client.label_error(NatsError::from((
+                    ErrorKind::Timeout,
+                    "No reply received before the timeout",
+                    subject.to_owned(),
+                ))))
+            }
+        }
+    }
+
+    // Waits until `take` returns an event
+    fn wait_until(
+        &self,
+        take: &dyn Fn(&mut Client) -> Option<Event>,
+        deadline: Option<Instant>,
+    ) -> Result<Option<Event>, NatsError> {
+        loop {
+            {
+                let mut reading = self.inner.reading.lock().unwrap();
+                if let Some(event) = take(&mut self.lock()) {
+                    return Ok(Some(event));
+                }
+                let timeout = match deadline {
+                    None => None,
+                    Some(deadline) => {
+                        let now = Instant::now();
+                        if now >= deadline {
+                            return Ok(None);
+                        }
+                        Some(deadline - now)
+                    }
+                };
+                if *reading {
+                    match timeout {
+                        None => drop(self.inner.events_available.wait(reading).unwrap()),
+                        Some(timeout) => drop(
+                            self.inner
+                                .events_available
+                                .wait_timeout(reading, timeout)
+                                .unwrap(),
+                        ),
+                    }
+                    continue;
+                }
+                *reading = true;
+            }
+            let res = self.read_once(deadline);
+            *self.inner.reading.lock().unwrap() = false;
+            self.inner.events_available.notify_all();
+            res?;
+        }
+    }
+
+    // Waits for data without holding the client, then reads a frame
+    fn read_once(&self, deadline: Option<Instant>) -> Result<(), NatsError> {
+        let (socket, flush_interval) = {
+            let mut client = self.lock();
+            (client.readable_socket()?, client.flush_interval())
+        };
+        if let Some(socket) = socket {
+            let timeout = deadline.map(|deadline| {
+                cmp::max(
+                    deadline.saturating_duration_since(Instant::now()),
+                    Duration::from_millis(1),
+                )
+            });
+            // Wakes up in time to flush what other threads publish meanwhile
+            let timeout = match (timeout, flush_interval) {
+                (Some(timeout), Some(interval)) => Some(cmp::min(timeout, interval)),
+                (timeout, interval) => timeout.or(interval),
+            };
+            // The socket is shared with the client, whose flags can't be
+            // changed from here. Errors are reported by the next read, which
+            // also reconnects.
+            if !stream::wait_readable(&socket, timeout).unwrap_or(true) {
+                return Ok(());
+            }
+        }
+        let mut client = self.lock();
+        if let Some(event) = client.poll_event()? {
+            client.push_pending_event(event);
+        }
+        Ok(())
+    }
+}
+
+fn slow_consumer(event: &Event, drop_event: bool, on_error: &mut dyn ErrorCallback) {
+    warn!(
+        "Slow consumer: {} (sid {}), {}",
+        event.subject,
+        event.channel.sid,
+        if drop_event {
+            "dropping messages"
+        } else {
+            "blocking"
+        }
+    );
+    on_error.call(&NatsError::from((
+        ErrorKind::SlowConsumer,
+        "Pending limits exceeded by a subscription",
+        format!("{} (sid {})", event.subject, event.channel.sid),
+    )));
+}
+
+// Receives the events of a subscription from the reader thread
+#[derive(Debug)]
+pub struct EventReceiver {
+    rx: Receiver<Event>,
+    pending: Arc<Pending>,
+}
+
+impl EventReceiver {
+    pub fn recv(&self) -> Result<Event, RecvError> {
+        self.rx.recv().inspect(|event| self.consumed(event))
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Event, RecvTimeoutError> {
+        self.rx
+            .recv_timeout(timeout)
+            .inspect(|event| self.consumed(event))
+    }
+
+    pub fn try_recv(&self) -> Result<Event, TryRecvError> {
+        self.rx.try_recv().inspect(|event| self.consumed(event))
+    }
+
+    // Receives events until the subscription is removed
+    pub fn iter(&self) -> impl Iterator<Item = Event> + '_ {
+        iter::from_fn(move || self.recv().ok())
+    }
+
+    // Number of messages and bytes queued, but not received yet
+    pub fn pending(&self) -> (usize, usize) {
+        let state = self.pending.state.lock().unwrap();
+        (state.messages, state.bytes)
+    }
+
+    // Number of messages dropped because the pending limits were exceeded
+    pub fn dropped(&self) -> u64 {
+        self.pending.state.lock().unwrap().dropped
+    }
+
+    fn consumed(&self, event: &Event) {
+        let mut state = self.pending.state.lock().unwrap();
+        state.messages -= 1;
+        state.bytes -= event.msg.len();
+        self.pending.consumed.notify_all();
+    }
+}
+
+impl Drop for EventReceiver {
+    fn drop(&mut self) {
+        self.pending.state.lock().unwrap().receiver_dropped = true;
+        self.pending.consumed.notify_all();
+    }
+}
+
+impl Subscription {
+    // Turns a subscription of the shared client into a standard receiver, to
+    // which the reader thread forwards up to `bound` events not received
+    // yet. The subscription is removed once the receiver is dropped and
+    // another event is received.
+    pub fn into_receiver(
+        self,
+        shared: &SharedClient,
+        bound: usize,
+        policy: SlowConsumerPolicy,
+    ) -> Receiver<Event> {
+        let (tx, rx) = mpsc::sync_channel(bound);
+        shared.route_to_channel(self.detach(), Arc::new(tx), policy);
+        rx
+    }
+}
+
+// Stops the reader thread when dropped
+#[derive(Debug)]
+pub struct ReaderHandle {
+    stop: Arc<AtomicBool>,
+    name: String,
+    shared: SharedClient,
+}
+
+impl ReaderHandle {
+    pub fn stop(self) {}
+}
+
+impl Drop for ReaderHandle {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        let thread = {
+            let mut threads = self.shared.inner.threads.lock().unwrap();
+            threads
+                .iter()
+                .position(|thread| thread.name == self.name)
+                .map(|pos| threads.remove(pos))
+        };
+        if let Some(thread) = thread {
+            let _ = thread.handle.join();
+        }
+    }
+}
+
+impl From<Client> for SharedClient {
+    fn from(client: Client) -> SharedClient {
+        SharedClient::new(client)
+    }
+}
+
+#[test]
+fn shared_client_test() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<SharedClient>();
+}
+
+#[test]
+fn pending_limits_test() {
+    use bytes::Bytes;
+    use std::time::SystemTime;
+
+    let shared = SharedClient::new(Client::new("nats://127.0.0.1").unwrap());
+    let (tx, rx) = mpsc::channel();
+    let pending = Arc::new(Pending::default());
+    pending.state.lock().unwrap().limits = PendingLimits {
+        max_messages: 2,
+        ..PendingLimits::default()
+    };
+    shared.inner.routes.lock().unwrap().insert(
+        1,
+        Route {
+            sink: Sink::Receiver(tx),
+            pending: pending.clone(),
+        },
+    );
+    let receiver = EventReceiver { rx, pending };
+    let event = Event {
+        subject: "subject".to_owned(),
+        channel: Channel { sid: 1 },
+        msg: Bytes::from_static(b"test"),
+        inbox: None,
+        headers: None,
+        received_at: SystemTime::now(),
+    };
+    let stop = AtomicBool::new(false);
+    let mut errors = Vec::new();
+    let mut on_error = |e: &NatsError| errors.push(e.kind());
+    for _ in 0..4 {
+        shared.route(event.clone(), &stop, &mut on_error).unwrap();
+    }
+    assert_eq!(receiver.pending(), (2, 8));
+    assert_eq!(receiver.dropped(), 2);
+    receiver.try_recv().unwrap();
+    assert_eq!(receiver.pending(), (1, 4));
+    shared.route(event, &stop, &mut on_error).unwrap();
+    assert_eq!(receiver.pending(), (2, 8));
+    assert_eq!(errors, vec![ErrorKind::SlowConsumer]);
+}
+
+#[test]
+fn channel_sink_test() {
+    use bytes::Bytes;
+    use std::time::SystemTime;
+
+    let shared = SharedClient::new(Client::new("nats://127.0.0.1").unwrap());
+    let (tx, rx) = mpsc::sync_channel(2);
+    shared.inner.routes.lock().unwrap().insert(
+        1,
+        Route {
+            sink: Sink::Channel(Arc::new(tx)),
+            pending: Arc::new(Pending::default()),
+        },
+    );
+    let event = Event {
+        subject: "subject".to_owned(),
+        channel: Channel { sid: 1 },
+        msg: Bytes::from_static(b"test"),
+        inbox: None,
+        headers: None,
+        received_at: SystemTime::now(),
+    };
+    let stop = AtomicBool::new(false);
+    let mut errors = Vec::new();
+    let mut on_error = |e: &NatsError| errors.push(e.kind());
+    for _ in 0..4 {
+        shared.route(event.clone(), &stop, &mut on_error).unwrap();
+    }
+    assert_eq!(rx.try_iter().count(), 2);
+    shared.route(event, &stop, &mut on_error).unwrap();
+    assert_eq!(rx.try_iter().count(), 1);
+    assert_eq!(errors, vec![ErrorKind::SlowConsumer]);
+}
+
+#[test]
+fn tls_pending_data_test() {
+    use crate::testing::self_signed_certificate;
+    use crate::tls_config::TlsConfigBuilder;
+    use openssl::ssl::{SslAcceptor, SslMethod};
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    let (cert, key) = self_signed_certificate("localhost", 1).unwrap();
+    let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+    acceptor.set_certificate(&cert).unwrap();
+    acceptor.set_private_key(&key).unwrap();
+    let acceptor = acceptor.build();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    // The first message is larger than the read buffer (8 KB) and spans two
+    // TLS records, ending on a multiple of the buffer size. The second one
+    // is then decrypted along with it, but the socket has nothing to read.
+    let server = thread::spawn(move || {
+        let (mut tcp, _) = listener.accept().unwrap();
+        tcp.write_all(b"INFO {\"tls_required\":true,\"max_payload\":1048576}\r\n")
+            .unwrap();
+        let mut stream = acceptor.accept(tcp).unwrap();
+        let mut received = Vec::new();
+        let mut buf = [0u8; 4096];
+        while let Ok(len) = stream.read(&mut buf) {
+            if len == 0 {
+                break;
+            }
+            received.extend_from_slice(&buf[..len]);
+            while let Some(end) = received.iter().position(|&c| c == b'\n') {
+                let line = String::from_utf8(received.drain(..=end).collect()).unwrap();
+                if line.starts_with("PING") {
+                    stream.write_all(b"PONG\r\n").unwrap();
+                } else if line.starts_with("SUB ") {
+                    let sid = line.split_whitespace().last().unwrap();
+                    let prefix = format!("MSG a {} ", sid);
+                    let len = 3 * 8192 - prefix.len() - "24000\r\n".len() - 2;
+                    let mut frames = format!("{}{}\r\n", prefix, len).into_bytes();
+                    frames.resize(3 * 8192 - 2, b'x');
+                    frames.extend_from_slice(format!("\r\nMSG a {} 2\r\nhi\r\n", sid).as_bytes());
+                    stream.write_all(&frames).unwrap();
+                }
+            }
+        }
+    });
+
+    let mut builder = TlsConfigBuilder::new().unwrap();
+    builder.add_root_certificate(cert).unwrap();
+    let mut client = Client::new(format!("nats://127.0.0.1:{}", port).as_str()).unwrap();
+    client.set_tls_config(builder.build());
+    let shared = SharedClient::new(client);
+    let (_, receiver) = shared.subscribe_with_receiver("a", None).unwrap();
+    let reader = shared.start_reader(|_: &NatsError| {});
+    let timeout = Duration::from_secs(2);
+    assert!(receiver.recv_timeout(timeout).unwrap().msg.len() > 8192);
+    assert_eq!(receiver.recv_timeout(timeout).unwrap().msg, &b"hi"[..]);
+    reader.stop();
+    shared.lock().close().unwrap();
+    server.join().unwrap();
+}
+
+#[test]
+fn idle_flush_test() {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (lines, received) = mpsc::channel();
+    let server = thread::spawn(move || {
+        let (mut tcp, _) = listener.accept().unwrap();
+        tcp.write_all(b"INFO {\"max_payload\":1048576}\r\n")
+            .unwrap();
+        let reader = BufReader::new(tcp.try_clone().unwrap());
+        for line in reader.lines() {
+            let line = line.unwrap();
+            if line == "PING" {
+                tcp.write_all(b"PONG\r\n").unwrap();
+            } else if line.starts_with("PUB ") {
+                let _ = lines.send(line);
+            }
+        }
+    });
+
+    let mut client = Client::new(format!("nats://127.0.0.1:{}", port).as_str()).unwrap();
+    client
+        .set_write_buffer(65536, Duration::from_millis(20))
+        .unwrap();
+    let shared = SharedClient::new(client);
+    shared.publish("a", b"first").unwrap();
+    let reader = shared.start_reader(|_: &NatsError| {});
+    assert_eq!(
+        received.recv_timeout(Duration::from_secs(1)).unwrap(),
+        "PUB a 5"
+    );
+    // Published while the reader thread waits for data, right after a flush,
+    // then left idle
+    thread::sleep(Duration::from_millis(30));
+    shared.publish("a", b"flushed").unwrap();
+    shared.publish("a", b"last").unwrap();
+    let timeout = Duration::from_millis(50);
+    assert_eq!(received.recv_timeout(timeout).unwrap(), "PUB a 7");
+    assert_eq!(received.recv_timeout(timeout).unwrap(), "PUB a 4");
+    reader.stop();
+    shared.lock().close().unwrap();
+    server.join().unwrap();
+}
+
+#[test]
+fn concurrent_publish_test() {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    const THREADS: usize = 8;
+    const MESSAGES: usize = 500;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = thread::spawn(move || {
+        let (mut tcp, _) = listener.accept().unwrap();
+        tcp.write_all(b"INFO {\"max_payload\":1048576}\r\n")
+            .unwrap();
+        let reader = BufReader::new(tcp.try_clone().unwrap());
+        let mut received = HashMap::new();
+        for line in reader.lines() {
+            let line = line.unwrap();
+            if line == "PING" {
+                tcp.write_all(b"PONG\r\n").unwrap();
+            } else if line.starts_with("PUB ") {
+                let subject = line.split(' ').nth(1).unwrap().to_owned();
+                *received.entry(subject).or_insert(0) += 1;
+            }
+        }
+        received
+    });
+
+    let shared =
+        SharedClient::new(Client::new(format!("nats://127.0.0.1:{}", port).as_str()).unwrap());
+    let reader = shared.start_reader(|_: &NatsError| {});
+    let publishers: Vec<_> = (0..THREADS)
+        .map(|i| {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                let subject = format!("thread.{}", i);
+                for _ in 0..MESSAGES {
+                    shared.publish(&subject, b"test").unwrap();
+                }
+            })
+        })
+        .collect();
+    for publisher in publishers {
+        publisher.join().unwrap();
+    }
+    reader.stop();
+    shared.lock().close().unwrap();
+    let received = server.join().unwrap();
+    assert_eq!(received.len(), THREADS);
+    assert!(received.values().all(|&count| count == MESSAGES));
+}
+
+#[test]
+fn stop_readers_test() {
+    use crate::ErrorKind;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    // Denies publications, reported to the error callback of the reader
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = thread::spawn(move || {
+        let (mut tcp, _) = listener.accept().unwrap();
+        tcp.write_all(b"INFO {\"max_payload\":1048576}\r\n")
+            .unwrap();
+        let reader = BufReader::new(tcp.try_clone().unwrap());
+        for line in reader.lines().map_while(Result::ok) {
+            if line == "PING" {
+                tcp.write_all(b"PONG\r\n").unwrap();
+            } else if line.starts_with("PUB ") {
+                tcp.write_all(b"-ERR 'Permissions Violation for Publish to \"a\"'\r\n")
+                    .unwrap();
+            }
+        }
+    });
+
+    let shared =
+        SharedClient::new(Client::new(format!("nats://127.0.0.1:{}", port).as_str()).unwrap());
+    shared.publish("a", b"").unwrap();
+    let (failed, errors) = mpsc::channel();
+    let _reader = shared.start_reader(move |_: &NatsError| {
+        failed.send(()).unwrap();
+        panic!("callback failed");
+    });
+    errors.recv_timeout(Duration::from_secs(5)).unwrap();
+    let e = shared.stop_readers(Duration::from_secs(10)).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::HandlerPanicked);
+    assert!(e.to_string().contains("nats-reader-1"));
+    assert!(shared.inner.threads.lock().unwrap().is_empty());
+    shared.stop_readers(Duration::from_secs(1)).unwrap();
+    shared.lock().close().unwrap();
+    server.join().unwrap();
+}
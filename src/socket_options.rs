@@ -0,0 +1,124 @@
+use socket2::{Domain, Protocol, Socket, TcpKeepalive, Type};
+use std::{
+    io,
+    net::{IpAddr, SocketAddr, TcpStream},
+    time::Duration,
+};
+
+// Options applied to the TCP socket of every connection. Options left to
+// `None` keep the system defaults.
+// `keepalive` is the idle time before keepalive probes are sent, and
+// `keepalive_interval` the time between probes.
+// `local_address` is the source address of connections, for hosts with
+// multiple interfaces; servers resolving to addresses of a different family
+// are skipped. `bind_interface` restricts connections to a network
+// interface, and is only supported on Linux and Android.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SocketOptions {
+    pub nodelay: bool,
+    pub keepalive: Option<Duration>,
+    pub keepalive_interval: Option<Duration>,
+    pub send_buffer_size: Option<usize>,
+    pub recv_buffer_size: Option<usize>,
+    pub local_address: Option<IpAddr>,
+    pub bind_interface: Option<String>,
+}
+
+impl SocketOptions {
+    // Connects to `addr`, with the options set before the connection is
+    // established
+    pub(crate) fn connect(
+        &self,
+        addr: &SocketAddr,
+        timeout: Option<Duration>,
+    ) -> io::Result<TcpStream> {
+        let socket = Socket::new(
+            Domain::for_address(*addr),
+            Type::STREAM,
+            Some(Protocol::TCP),
+        )?;
+        if let Some(ip) = self.local_address {
+            if ip.is_ipv4() != addr.is_ipv4() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrNotAvailable,
+                    "The local address and the server address are of different families",
+                ));
+            }
+            socket.bind(&SocketAddr::new(ip, 0).into())?;
+        }
+        if let Some(ref interface) = self.bind_interface {
+            bind_device(&socket, interface)?;
+        }
+        self.apply(&socket)?;
+        match timeout {
+            Some(timeout) => socket.connect_timeout(&(*addr).into(), timeout)?,
+            None => socket.connect(&(*addr).into())?,
+        }
+        Ok(socket.into())
+    }
+
+    fn apply(&self, socket: &Socket) -> io::Result<()> {
+        socket.set_nodelay(self.nodelay)?;
+        if let Some(time) = self.keepalive {
+            let mut keepalive = TcpKeepalive::new().with_time(time);
+            if let Some(interval) = self.keepalive_interval {
+                keepalive = keepalive.with_interval(interval);
+            }
+            socket.set_tcp_keepalive(&keepalive)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn bind_device(socket: &Socket, interface: &str) -> io::Result<()> {
+    socket.bind_device(Some(interface.as_bytes()))
+}
+
+#[cfg(not(any(target_os = "android", target_os = "linux")))]
+fn bind_device(_socket: &Socket, _interface: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "Binding to an interface is not supported on this platform",
+    ))
+}
+
+#[test]
+fn socket_options_test() {
+    use socket2::SockRef;
+    use std::net::{Ipv4Addr, Ipv6Addr, TcpListener};
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let options = SocketOptions {
+        nodelay: true,
+        keepalive: Some(Duration::from_secs(30)),
+        keepalive_interval: Some(Duration::from_secs(5)),
+        send_buffer_size: Some(65536),
+        local_address: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+        ..Default::default()
+    };
+    let tcp = options
+        .connect(&addr, Some(Duration::from_secs(1)))
+        .unwrap();
+    assert_eq!(tcp.local_addr().unwrap().ip(), Ipv4Addr::LOCALHOST);
+    let socket = SockRef::from(&tcp);
+    assert!(socket.nodelay().unwrap());
+    assert!(socket.keepalive().unwrap());
+    assert!(socket.send_buffer_size().unwrap() >= 65536);
+
+    let options = SocketOptions {
+        local_address: Some(IpAddr::V6(Ipv6Addr::LOCALHOST)),
+        ..Default::default()
+    };
+    assert_eq!(
+        options.connect(&addr, None).unwrap_err().kind(),
+        io::ErrorKind::AddrNotAvailable
+    );
+}
@@ -0,0 +1,709 @@
+use crate::client::{no_responders_check, Channel, Client, Event};
+use crate::errors::*;
+use crate::nuid::{IdGenerator, Nuid};
+use bytes::Bytes;
+use std::{
+    cmp,
+    collections::HashMap,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+const DISCOVER_PREFIX: &str = "_STAN.discover";
+const PROTOCOL_VERSION: i32 = 1;
+const DEFAULT_ACK_WAIT_SECS: i32 = 30;
+const DEFAULT_MAX_IN_FLIGHT: i32 = 1024;
+const DEFAULT_PING_INTERVAL_SECS: i32 = 5;
+const DEFAULT_PING_MAX_OUT: i32 = 3;
+const DEFAULT_TIMEOUT_MS: u64 = 5000;
+
+const WIRE_VARINT: u8 = 0;
+const WIRE_FIXED64: u8 = 1;
+const WIRE_BYTES: u8 = 2;
+const WIRE_FIXED32: u8 = 5;
+
+// Minimal protobuf (proto3) encoder for the NATS Streaming messages
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+    fn new() -> Encoder {
+        Encoder(Vec::new())
+    }
+
+    fn varint(&mut self, mut v: u64) {
+        while v >= 0x80 {
+            self.0.push((v as u8) | 0x80);
+            v >>= 7;
+        }
+        self.0.push(v as u8);
+    }
+
+    fn key(&mut self, field: u32, wire_type: u8) {
+        self.varint(u64::from(field) << 3 | u64::from(wire_type));
+    }
+
+    fn bytes(&mut self, field: u32, v: &[u8]) {
+        if v.is_empty() {
+            return;
+        }
+        self.key(field, WIRE_BYTES);
+        self.varint(v.len() as u64);
+        self.0.extend_from_slice(v);
+    }
+
+    fn string(&mut self, field: u32, v: &str) {
+        self.bytes(field, v.as_bytes())
+    }
+
+    fn uint64(&mut self, field: u32, v: u64) {
+        if v == 0 {
+            return;
+        }
+        self.key(field, WIRE_VARINT);
+        self.varint(v);
+    }
+
+    fn int64(&mut self, field: u32, v: i64) {
+        self.uint64(field, v as u64)
+    }
+
+    fn int32(&mut self, field: u32, v: i32) {
+        self.uint64(field, i64::from(v) as u64)
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+enum Field<'t> {
+    Varint(u64),
+    Bytes(&'t [u8]),
+}
+
+impl<'t> Field<'t> {
+    fn as_u64(&self) -> u64 {
+        match *self {
+            Field::Varint(v) => v,
+            Field::Bytes(_) => 0,
+        }
+    }
+
+    fn as_string(&self) -> Result<String, NatsError> {
+        match *self {
+            Field::Bytes(v) => Ok(std::str::from_utf8(v)?.to_owned()),
+            Field::Varint(_) => Ok(String::new()),
+        }
+    }
+
+    fn as_bytes(&self) -> Vec<u8> {
+        match *self {
+            Field::Bytes(v) => v.to_vec(),
+            Field::Varint(_) => Vec::new(),
+        }
+    }
+}
+
+fn invalid_message() -> NatsError {
+    NatsError::from((
+        ErrorKind::ServerProtocolError,
+        "Invalid NATS Streaming message",
+    ))
+}
+
+fn decode_varint(buf: &[u8], pos: &mut usize) -> Result<u64, NatsError> {
+    let mut v: u64 = 0;
+    for shift in (0..64).step_by(7) {
+        let c = *buf.get(*pos).ok_or_else(invalid_message)?;
+        *pos += 1;
+        v |= u64::from(c & 0x7f) << shift;
+        if c & 0x80 == 0 {
+            return Ok(v);
+        }
+    }
+    Err(invalid_message())
+}
+
+fn decode_fields<F>(buf: &[u8], mut f: F) -> Result<(), NatsError>
+where
+    F: FnMut(u32, Field<'_>) -> Result<(), NatsError>,
+{
+    let mut pos = 0;
+    while pos < buf.len() {
+        let key = decode_varint(buf, &mut pos)?;
+        let field = (key >> 3) as u32;
+        match (key & 0x7) as u8 {
+            WIRE_VARINT => f(field, Field::Varint(decode_varint(buf, &mut pos)?))?,
+            WIRE_BYTES => {
+                let len = decode_varint(buf, &mut pos)? as usize;
+                let end = pos.checked_add(len).ok_or_else(invalid_message)?;
+                let v = buf.get(pos..end).ok_or_else(invalid_message)?;
+                pos = end;
+                f(field, Field::Bytes(v))?
+            }
+            WIRE_FIXED64 => pos += 8,
+            WIRE_FIXED32 => pos += 4,
+            _ => return Err(invalid_message()),
+        }
+    }
+    if pos != buf.len() {
+        return Err(invalid_message());
+    }
+    Ok(())
+}
+
+fn check_error(error: String) -> Result<(), NatsError> {
+    if error.is_empty() {
+        Ok(())
+    } else {
+        Err(NatsError::from((
+            ErrorKind::ServerProtocolError,
+            "NATS Streaming server error",
+            error,
+        )))
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct ConnectResponse {
+    pub_prefix: String,
+    sub_requests: String,
+    unsub_requests: String,
+    close_requests: String,
+    sub_close_requests: String,
+    ping_requests: String,
+    ping_interval: i32,
+    ping_max_out: i32,
+    error: String,
+}
+
+impl ConnectResponse {
+    fn decode(buf: &[u8]) -> Result<ConnectResponse, NatsError> {
+        let mut res = ConnectResponse::default();
+        decode_fields(buf, |field, v| {
+            match field {
+                1 => res.pub_prefix = v.as_string()?,
+                2 => res.sub_requests = v.as_string()?,
+                3 => res.unsub_requests = v.as_string()?,
+                4 => res.close_requests = v.as_string()?,
+                5 => res.error = v.as_string()?,
+                6 => res.sub_close_requests = v.as_string()?,
+                7 => res.ping_requests = v.as_string()?,
+                8 => res.ping_interval = v.as_u64() as i32,
+                9 => res.ping_max_out = v.as_u64() as i32,
+                _ => {}
+            }
+            Ok(())
+        })?;
+        Ok(res)
+    }
+}
+
+// Response types that only carry an error string at a given field number
+fn decode_error(buf: &[u8], error_field: u32) -> Result<String, NatsError> {
+    let mut error = String::new();
+    decode_fields(buf, |field, v| {
+        if field == error_field {
+            error = v.as_string()?;
+        }
+        Ok(())
+    })?;
+    Ok(error)
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+pub enum StartPosition {
+    #[default]
+    NewOnly,
+    LastReceived,
+    TimeDeltaStart(Duration),
+    SequenceStart(u64),
+    First,
+}
+
+#[derive(Clone, Debug)]
+pub struct StanSubscribeOptions {
+    pub durable_name: Option<String>,
+    pub max_in_flight: i32,
+    pub ack_wait: Duration,
+    pub start_position: StartPosition,
+    pub manual_acks: bool,
+}
+
+impl Default for StanSubscribeOptions {
+    fn default() -> StanSubscribeOptions {
+        StanSubscribeOptions {
+            durable_name: None,
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+            ack_wait: Duration::from_secs(DEFAULT_ACK_WAIT_SECS as u64),
+            start_position: StartPosition::default(),
+            manual_acks: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StanSubscription {
+    pub channel: Channel,
+    pub subject: String,
+    ack_inbox: String,
+    durable_name: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct StanMsg {
+    pub sequence: u64,
+    pub subject: String,
+    pub reply: Option<String>,
+    pub data: Vec<u8>,
+    pub timestamp: SystemTime,
+    pub redelivered: bool,
+    pub redelivery_count: u32,
+    pub channel: Channel,
+}
+
+impl StanMsg {
+    fn decode(buf: &[u8], channel: Channel) -> Result<StanMsg, NatsError> {
+        let mut msg = StanMsg {
+            sequence: 0,
+            subject: String::new(),
+            reply: None,
+            data: Vec::new(),
+            timestamp: UNIX_EPOCH,
+            redelivered: false,
+            redelivery_count: 0,
+            channel,
+        };
+        decode_fields(buf, |field, v| {
+            match field {
+                1 => msg.sequence = v.as_u64(),
+                2 => msg.subject = v.as_string()?,
+                3 => msg.reply = Some(v.as_string()?).filter(|r| !r.is_empty()),
+                4 => msg.data = v.as_bytes(),
+                5 => msg.timestamp = UNIX_EPOCH + Duration::from_nanos(v.as_u64()),
+                6 => msg.redelivered = v.as_u64() != 0,
+                7 => msg.redelivery_count = v.as_u64() as u32,
+                _ => {}
+            }
+            Ok(())
+        })?;
+        Ok(msg)
+    }
+}
+
+#[derive(Debug)]
+struct StanSubscriptionState {
+    ack_inbox: String,
+    manual_acks: bool,
+}
+
+// NATS Streaming session layered over a core NATS client
+#[derive(Debug)]
+pub struct StanClient {
+    client: Client,
+    cluster_id: String,
+    client_id: String,
+    conn_id: String,
+    heartbeat: Channel,
+    info: ConnectResponse,
+    nuid: Nuid,
+    subscriptions: HashMap<u64, StanSubscriptionState>,
+    timeout: Duration,
+    pings: Option<PingState>,
+}
+
+// Pings sent to the server, which answers with an error once it doesn't
+// know the connection any more
+#[derive(Debug)]
+struct PingState {
+    requests: String,
+    interval: Duration,
+    max_out: u32,
+    out: u32,
+    next: Instant,
+    inbox: Option<String>,
+}
+
+impl StanClient {
+    pub fn connect(
+        client: Client,
+        cluster_id: &str,
+        client_id: &str,
+    ) -> Result<StanClient, NatsError> {
+        StanClient::connect_with_timeout(
+            client,
+            cluster_id,
+            client_id,
+            Duration::from_millis(DEFAULT_TIMEOUT_MS),
+        )
+    }
+
+    // `timeout` bounds every request to the streaming server, including
+    // publish acknowledgements
+    pub fn connect_with_timeout(
+        mut client: Client,
+        cluster_id: &str,
+        client_id: &str,
+        timeout: Duration,
+    ) -> Result<StanClient, NatsError> {
+        let mut nuid = Nuid::new();
+        let conn_id = nuid.next_id();
+        let heartbeat_inbox = format!("_STAN.HB.{}", nuid.next_id());
+        let heartbeat = client.subscribe(&heartbeat_inbox, None)?;
+        let mut req = Encoder::new();
+        req.string(1, client_id);
+        req.string(2, &heartbeat_inbox);
+        req.int32(3, PROTOCOL_VERSION);
+        req.string(4, &conn_id);
+        req.int32(5, DEFAULT_PING_INTERVAL_SECS);
+        req.int32(6, DEFAULT_PING_MAX_OUT);
+        let mut stan = StanClient {
+            client,
+            cluster_id: cluster_id.to_owned(),
+            client_id: client_id.to_owned(),
+            conn_id,
+            heartbeat,
+            info: ConnectResponse::default(),
+            nuid,
+            subscriptions: HashMap::new(),
+            timeout,
+            pings: None,
+        };
+        let subject = format!("{}.{}", DISCOVER_PREFIX, stan.cluster_id);
+        let reply = stan.request(&subject, &req.into_bytes())?;
+        let info = ConnectResponse::decode(&reply)?;
+        check_error(info.error.clone())?;
+        // Servers predating client pings don't announce a subject for them
+        if !info.ping_requests.is_empty() {
+            let interval = if info.ping_interval > 0 {
+                info.ping_interval
+            } else {
+                DEFAULT_PING_INTERVAL_SECS
+            };
+            let max_out = if info.ping_max_out > 0 {
+                info.ping_max_out
+            } else {
+                DEFAULT_PING_MAX_OUT
+            };
+            let interval = Duration::from_secs(interval as u64);
+            stan.pings = Some(PingState {
+                requests: info.ping_requests.clone(),
+                interval,
+                max_out: max_out as u32,
+                out: 0,
+                next: Instant::now() + interval,
+                inbox: None,
+            });
+        }
+        stan.info = info;
+        Ok(stan)
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    pub fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    pub fn publish(&mut self, subject: &str, msg: &[u8]) -> Result<(), NatsError> {
+        let guid = self.nuid.next_id();
+        let mut req = Encoder::new();
+        req.string(1, &self.client_id);
+        req.string(2, &guid);
+        req.string(3, subject);
+        req.bytes(5, msg);
+        req.string(6, &self.conn_id);
+        let pub_subject = format!("{}.{}", self.info.pub_prefix, subject);
+        let reply = self.request(&pub_subject, &req.into_bytes())?;
+        check_error(decode_error(&reply, 2)?)
+    }
+
+    pub fn subscribe(
+        &mut self,
+        subject: &str,
+        queue: Option<&str>,
+        options: StanSubscribeOptions,
+    ) -> Result<StanSubscription, NatsError> {
+        let inbox = format!("_STAN.SUB.{}", self.nuid.next_id());
+        let channel = self.client.subscribe(&inbox, None)?;
+        let mut req = Encoder::new();
+        req.string(1, &self.client_id);
+        req.string(2, subject);
+        req.string(3, queue.unwrap_or(""));
+        req.string(4, &inbox);
+        req.int32(5, options.max_in_flight);
+        req.int32(6, options.ack_wait.as_secs() as i32);
+        req.string(7, options.durable_name.as_deref().unwrap_or(""));
+        let (position, start_sequence, start_time_delta) = match options.start_position {
+            StartPosition::NewOnly => (0, 0, 0),
+            StartPosition::LastReceived => (1, 0, 0),
+            StartPosition::TimeDeltaStart(delta) => (2, 0, delta.as_nanos() as i64),
+            StartPosition::SequenceStart(seq) => (3, seq, 0),
+            StartPosition::First => (4, 0, 0),
+        };
+        req.int32(10, position);
+        req.uint64(11, start_sequence);
+        req.int64(12, start_time_delta);
+        let sub_requests = self.info.sub_requests.clone();
+        let res = self
+            .request(&sub_requests, &req.into_bytes())
+            .and_then(|reply| {
+                let mut ack_inbox = String::new();
+                let mut error = String::new();
+                decode_fields(&reply, |field, v| {
+                    match field {
+                        2 => ack_inbox = v.as_string()?,
+                        3 => error = v.as_string()?,
+                        _ => {}
+                    }
+                    Ok(())
+                })?;
+                check_error(error)?;
+                Ok(ack_inbox)
+            });
+        let ack_inbox = match res {
+            Ok(ack_inbox) => ack_inbox,
+            Err(e) => {
+                let _ = self.client.unsubscribe(channel);
+                return Err(e);
+            }
+        };
+        self.subscriptions.insert(
+            channel.sid,
+            StanSubscriptionState {
+                ack_inbox: ack_inbox.clone(),
+                manual_acks: options.manual_acks,
+            },
+        );
+        Ok(StanSubscription {
+            channel,
+            subject: subject.to_owned(),
+            ack_inbox,
+            durable_name: options.durable_name,
+        })
+    }
+
+    pub fn unsubscribe(&mut self, sub: StanSubscription) -> Result<(), NatsError> {
+        let unsub_requests = self.info.unsub_requests.clone();
+        self.remove_subscription(sub, &unsub_requests)
+    }
+
+    // Closes the subscription while keeping the durable's state on the server
+    pub fn close_subscription(&mut self, sub: StanSubscription) -> Result<(), NatsError> {
+        if self.info.sub_close_requests.is_empty() {
+            return Err(NatsError::from((
+                ErrorKind::ClientProtocolError,
+                "The server doesn't support closing subscriptions",
+            )));
+        }
+        let sub_close_requests = self.info.sub_close_requests.clone();
+        self.remove_subscription(sub, &sub_close_requests)
+    }
+
+    fn remove_subscription(
+        &mut self,
+        sub: StanSubscription,
+        requests_subject: &str,
+    ) -> Result<(), NatsError> {
+        let mut req = Encoder::new();
+        req.string(1, &self.client_id);
+        req.string(2, &sub.subject);
+        req.string(3, &sub.ack_inbox);
+        req.string(4, sub.durable_name.as_deref().unwrap_or(""));
+        self.subscriptions.remove(&sub.channel.sid);
+        self.client.unsubscribe(sub.channel)?;
+        let reply = self.request(requests_subject, &req.into_bytes())?;
+        check_error(decode_error(&reply, 2)?)
+    }
+
+    pub fn ack(&mut self, msg: &StanMsg) -> Result<(), NatsError> {
+        let ack_inbox = match self.subscriptions.get(&msg.channel.sid) {
+            None => return Ok(()),
+            Some(sub) => sub.ack_inbox.clone(),
+        };
+        let mut ack = Encoder::new();
+        ack.string(1, &msg.subject);
+        ack.uint64(2, msg.sequence);
+        self.client.publish(&ack_inbox, &ack.into_bytes())
+    }
+
+    pub fn wait(&mut self) -> Result<StanMsg, NatsError> {
+        loop {
+            let event = match self.next_event(None, None)? {
+                Some(event) => event,
+                None => continue,
+            };
+            let manual_acks = match self.subscriptions.get(&event.channel.sid) {
+                None => continue,
+                Some(sub) => sub.manual_acks,
+            };
+            let msg = StanMsg::decode(&event.msg, event.channel)?;
+            if !manual_acks {
+                self.ack(&msg)?;
+            }
+            return Ok(msg);
+        }
+    }
+
+    pub fn close(mut self) -> Result<Client, NatsError> {
+        let mut req = Encoder::new();
+        req.string(1, &self.client_id);
+        let close_requests = self.info.close_requests.clone();
+        let reply = self.request(&close_requests, &req.into_bytes())?;
+        check_error(decode_error(&reply, 1)?)?;
+        self.client.unsubscribe(self.heartbeat)?;
+        for (sid, _) in self.subscriptions.drain() {
+            self.client.unsubscribe(Channel { sid })?;
+        }
+        if let Some(inbox) = self.pings.take().and_then(|pings| pings.inbox) {
+            self.client.cancel_request(&inbox);
+        }
+        Ok(self.client)
+    }
+
+    fn request(&mut self, subject: &str, msg: &[u8]) -> Result<Bytes, NatsError> {
+        let deadline = Instant::now() + self.timeout;
+        let inbox = self.client.make_request(subject, msg)?;
+        match self.next_event(Some(&inbox), Some(deadline)) {
+            Ok(Some(event)) => no_responders_check(subject, event).map(|event| event.msg),
+            Ok(None) => {
+                self.client.cancel_request(&inbox);
+                Err(NatsError::from((
+                    ErrorKind::Timeout,
+                    "No reply received from the streaming server before the timeout",
+                    subject.to_owned(),
+                )))
+            }
+            Err(e) => {
+                self.client.cancel_request(&inbox);
+                Err(e)
+            }
+        }
+    }
+
+    // Returns the reply to `inbox`, or the next event if `inbox` is `None`.
+    // Heartbeats are answered and pings sent while waiting, so that the
+    // server doesn't expel the client, and other events stay pending.
+    fn next_event(
+        &mut self,
+        inbox: Option<&str>,
+        deadline: Option<Instant>,
+    ) -> Result<Option<Event>, NatsError> {
+        loop {
+            self.check_ping_reply()?;
+            if let Some(event) = self.client.take_pending_event(Some(&[self.heartbeat])) {
+                if let Some(inbox) = event.inbox {
+                    self.client.publish(&inbox, b"")?;
+                }
+                continue;
+            }
+            let event = match inbox {
+                Some(inbox) => self.client.take_reply(inbox),
+                None => self.client.take_pending_event(None),
+            };
+            if event.is_some() {
+                return Ok(event);
+            }
+            let now = Instant::now();
+            if deadline.is_some_and(|deadline| now >= deadline) {
+                return Ok(None);
+            }
+            let next_ping = match self.pings {
+                Some(ref pings) if now >= pings.next => {
+                    self.send_ping()?;
+                    continue;
+                }
+                Some(ref pings) => pings.next,
+                None => now + Duration::from_secs(DEFAULT_PING_INTERVAL_SECS as u64),
+            };
+            let wake_up = deadline.map_or(next_ping, |deadline| cmp::min(deadline, next_ping));
+            self.client.read_until_deadline(wake_up)?;
+        }
+    }
+
+    fn send_ping(&mut self) -> Result<(), NatsError> {
+        let pings = self.pings.as_mut().unwrap();
+        pings.out += 1;
+        if pings.out > pings.max_out {
+            return Err(NatsError::from((
+                ErrorKind::Timeout,
+                "The streaming server stopped answering pings",
+            )));
+        }
+        pings.next = Instant::now() + pings.interval;
+        let requests = pings.requests.clone();
+        let previous = pings.inbox.take();
+        if let Some(previous) = previous {
+            self.client.cancel_request(&previous);
+        }
+        let mut ping = Encoder::new();
+        ping.string(1, &self.conn_id);
+        let inbox = self.client.make_request(&requests, &ping.into_bytes())?;
+        self.pings.as_mut().unwrap().inbox = Some(inbox);
+        Ok(())
+    }
+
+    fn check_ping_reply(&mut self) -> Result<(), NatsError> {
+        let inbox = match self.pings.as_ref().and_then(|pings| pings.inbox.clone()) {
+            None => return Ok(()),
+            Some(inbox) => inbox,
+        };
+        let event = match self.client.take_reply(&inbox) {
+            None => return Ok(()),
+            Some(event) => event,
+        };
+        let pings = self.pings.as_mut().unwrap();
+        pings.inbox = None;
+        pings.out = 0;
+        let error = decode_error(&event.msg, 1)?;
+        if error.is_empty() {
+            return Ok(());
+        }
+        Err(NatsError::from((
+            ErrorKind::ConnectionClosed,
+            "The streaming server closed the connection",
+            error,
+        )))
+    }
+}
+
+#[test]
+fn stan_proto_test() {
+    let mut enc = Encoder::new();
+    enc.uint64(1, 300);
+    enc.string(2, "orders");
+    enc.string(3, "");
+    enc.bytes(4, b"payload");
+    enc.int64(5, 1_600_000_000_000_000_000);
+    enc.uint64(6, 1);
+    enc.uint64(7, 2);
+    let msg = StanMsg::decode(&enc.into_bytes(), Channel { sid: 1 }).unwrap();
+    assert_eq!(msg.sequence, 300);
+    assert_eq!(msg.subject, "orders");
+    assert_eq!(msg.reply, None);
+    assert_eq!(msg.data, b"payload");
+    assert_eq!(
+        msg.timestamp,
+        UNIX_EPOCH + Duration::from_secs(1_600_000_000)
+    );
+    assert!(msg.redelivered);
+    assert_eq!(msg.redelivery_count, 2);
+
+    let mut enc = Encoder::new();
+    enc.int32(1, -1);
+    assert_eq!(enc.into_bytes().len(), 11);
+
+    assert!(StanMsg::decode(&[0x12, 0x05, b'a'], Channel { sid: 1 }).is_err());
+
+    let mut enc = Encoder::new();
+    enc.string(1, "_STAN.pub");
+    enc.string(7, "_STAN.ping");
+    enc.int32(8, 10);
+    enc.int32(9, 2);
+    let info = ConnectResponse::decode(&enc.into_bytes()).unwrap();
+    assert_eq!(info.pub_prefix, "_STAN.pub");
+    assert_eq!(info.ping_requests, "_STAN.ping");
+    assert_eq!(info.ping_interval, 10);
+    assert_eq!(info.ping_max_out, 2);
+}
@@ -2,8 +2,9 @@ use openssl;
 
 use self::openssl::ssl;
 use std::io;
-use std::net::TcpStream;
+use std::net::{Shutdown, TcpStream};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use self::Stream::{Ssl, Tcp};
 
@@ -27,6 +28,83 @@ impl Stream {
             Ssl(ref s) => s.as_tcp(),
         }
     }
+
+    pub fn shutdown(&self) -> io::Result<()> {
+        match *self {
+            Tcp(ref s) => s.shutdown(Shutdown::Both),
+            Ssl(ref s) => s.shutdown(),
+        }
+    }
+
+    // Data already decrypted by the TLS layer, that waiting for the socket to
+    // be readable wouldn't notice
+    pub fn has_pending(&self) -> bool {
+        match *self {
+            Tcp(_) => false,
+            Ssl(ref s) => s.0.lock().unwrap().ssl().pending() > 0,
+        }
+    }
+}
+
+// Waits until the socket has data to read, or `timeout` elapses. The flags
+// and timeouts of the socket are left untouched, as they are shared with the
+// other handles of the connection. `None` waits forever.
+// The standard library can only wait with a timeout by setting one on the
+// socket, which would also apply to a thread concurrently reading through
+// another handle, hence poll(2). libc is already required by socket2 on
+// unix, unlike mio, which remains optional.
+#[cfg(unix)]
+pub fn wait_readable(socket: &TcpStream, timeout: Option<Duration>) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    let timeout_ms = match timeout {
+        None => -1,
+        Some(timeout) => {
+            let ms = timeout.as_nanos().div_ceil(1_000_000);
+            ms.min(libc::c_int::MAX as u128) as libc::c_int
+        }
+    };
+    let mut fd = libc::pollfd {
+        fd: socket.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    loop {
+        // `fd` is a single, initialized pollfd that outlives the call
+        match unsafe { libc::poll(&mut fd, 1, timeout_ms) } {
+            0 => return Ok(false),
+            -1 => {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted {
+                    return Err(e);
+                }
+            }
+            // Errors and hangups are reported by the next read
+            _ => return Ok(true),
+        }
+    }
+}
+
+// Without poll(2), a read timeout is set on the socket for the duration of
+// the wait
+#[cfg(not(unix))]
+pub fn wait_readable(socket: &TcpStream, timeout: Option<Duration>) -> io::Result<bool> {
+    if timeout == Some(Duration::from_secs(0)) {
+        socket.set_nonblocking(true)?;
+    } else {
+        socket.set_read_timeout(timeout)?;
+    }
+    let res = socket.peek(&mut [0u8; 1]);
+    socket.set_nonblocking(false)?;
+    socket.set_read_timeout(None)?;
+    match res {
+        Err(ref e)
+            if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+        {
+            Ok(false)
+        }
+        _ => Ok(true),
+    }
 }
 
 impl io::Read for Stream {
@@ -46,6 +124,13 @@ impl io::Write for Stream {
         }
     }
 
+    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        match *self {
+            Tcp(ref mut s) => s.write_vectored(bufs),
+            Ssl(ref mut s) => s.write_vectored(bufs),
+        }
+    }
+
     fn flush(&mut self) -> io::Result<()> {
         match *self {
             Tcp(ref mut s) => s.flush(),
@@ -66,6 +151,13 @@ impl SslStream {
     pub fn as_tcp(&self) -> io::Result<TcpStream> {
         self.0.lock().unwrap().get_ref().try_clone()
     }
+
+    // Sends a TLS close_notify alert before closing the TCP connection
+    pub fn shutdown(&self) -> io::Result<()> {
+        let mut stream = self.0.lock().unwrap();
+        let _ = stream.shutdown();
+        stream.get_ref().shutdown(Shutdown::Both)
+    }
 }
 
 impl io::Read for SslStream {
@@ -0,0 +1,109 @@
+use std::{fmt, ops::Deref};
+
+// Subject validated at compile time by the `subject!` macro
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Subject(&'static str);
+
+impl Subject {
+    // Panics if the subject is invalid; meant to be evaluated in a const context
+    pub const fn from_static(subject: &'static str) -> Subject {
+        if !is_valid_subject(subject) {
+            panic!("Invalid NATS subject");
+        }
+        Subject(subject)
+    }
+
+    pub const fn as_str(&self) -> &'static str {
+        self.0
+    }
+}
+
+impl Deref for Subject {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.0
+    }
+}
+
+impl AsRef<str> for Subject {
+    fn as_ref(&self) -> &str {
+        self.0
+    }
+}
+
+impl fmt::Display for Subject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+pub const fn is_valid_subject(subject: &str) -> bool {
+    subject_error(subject, true).is_none()
+}
+
+// Returns why a subject is invalid, if it is. Tokens must be non-empty and
+// free of whitespace and control characters, wildcards must be complete
+// tokens, and `>` must be the last token. Evaluated at compile time by the
+// `subject!` macro, and at runtime for every subject sent to the server.
+pub(crate) const fn subject_error(subject: &str, wildcards: bool) -> Option<&'static str> {
+    let s = subject.as_bytes();
+    if s.is_empty() {
+        return Some("empty subject");
+    }
+    let mut i = 0;
+    let mut token_start = 0;
+    while i <= s.len() {
+        if i == s.len() || s[i] == b'.' {
+            if i == token_start {
+                return Some("empty token");
+            }
+            token_start = i + 1;
+        } else {
+            let c = s[i];
+            if c <= b' ' || c == 0x7f {
+                return Some("whitespace or control character");
+            }
+            if c == b'*' || c == b'>' {
+                if !wildcards {
+                    return Some("wildcards are not allowed");
+                }
+                if i != token_start || (i + 1 < s.len() && s[i + 1] != b'.') {
+                    return Some("wildcards must be complete tokens");
+                }
+                if c == b'>' && i + 1 != s.len() {
+                    return Some("'>' must be the last token");
+                }
+            }
+        }
+        i += 1;
+    }
+    None
+}
+
+#[macro_export]
+macro_rules! subject {
+    ($subject:expr) => {{
+        const SUBJECT: $crate::Subject = $crate::Subject::from_static($subject);
+        &SUBJECT
+    }};
+}
+
+#[test]
+fn subject_test() {
+    let subject: &'static Subject = subject!("orders.*.created");
+    assert_eq!(subject.as_str(), "orders.*.created");
+    assert!(is_valid_subject("orders.>"));
+    assert!(is_valid_subject("_INBOX.abc"));
+    assert!(!is_valid_subject("orders..created"));
+    assert!(!is_valid_subject("orders.>.created"));
+    assert!(!is_valid_subject("orders.cre*"));
+    assert!(!is_valid_subject("orders.*x"));
+    assert!(!is_valid_subject("orders.created."));
+    assert!(!is_valid_subject("orders created"));
+    assert!(!is_valid_subject(""));
+    assert_eq!(
+        subject_error("orders.*", false),
+        Some("wildcards are not allowed")
+    );
+}
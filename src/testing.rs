@@ -0,0 +1,288 @@
+use crate::client::Client;
+use crate::errors::*;
+use crate::nuid::{IdGenerator, Nuid};
+use openssl::{
+    asn1::Asn1Time,
+    bn::BigNum,
+    ec::{EcGroup, EcKey},
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::{PKey, Private},
+    ssl::{SslAcceptor, SslMethod},
+    x509::{extension::SubjectAlternativeName, X509NameBuilder, X509},
+};
+use std::{
+    env, fs,
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+const SERVER_BINARY_ENV: &str = "NATS_SERVER_BIN";
+const DEFAULT_READY_TIMEOUT_MS: u64 = 10_000;
+const READY_POLL_INTERVAL_MS: u64 = 50;
+
+// Starts a local `nats-server` for integration tests
+#[derive(Clone, Debug, Default)]
+pub struct ServerProcessBuilder {
+    binary: Option<PathBuf>,
+    port: Option<u16>,
+    jetstream: bool,
+    tls: Option<(PathBuf, PathBuf)>,
+    ready_timeout: Option<Duration>,
+}
+
+impl ServerProcessBuilder {
+    pub fn new() -> ServerProcessBuilder {
+        ServerProcessBuilder::default()
+    }
+
+    // Path to the server binary. By default, `$NATS_SERVER_BIN` is used if
+    // set, then `nats-server` is looked up in the `PATH`.
+    pub fn binary<P: AsRef<Path>>(&mut self, binary: P) -> &mut Self {
+        self.binary = Some(binary.as_ref().to_owned());
+        self
+    }
+
+    // A free port is picked if none is set
+    pub fn port(&mut self, port: u16) -> &mut Self {
+        self.port = Some(port);
+        self
+    }
+
+    // Enables JetStream, storing data in the temporary directory
+    pub fn jetstream(&mut self, jetstream: bool) -> &mut Self {
+        self.jetstream = jetstream;
+        self
+    }
+
+    pub fn tls<P: AsRef<Path>>(&mut self, cert_file: P, key_file: P) -> &mut Self {
+        self.tls = Some((cert_file.as_ref().to_owned(), key_file.as_ref().to_owned()));
+        self
+    }
+
+    pub fn ready_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.ready_timeout = Some(timeout);
+        self
+    }
+
+    pub fn start(&self) -> Result<ServerProcess, NatsError> {
+        let binary = match self.binary {
+            Some(ref binary) => binary.clone(),
+            None => find_server_binary()?,
+        };
+        let port = match self.port {
+            Some(port) => port,
+            None => TcpListener::bind("127.0.0.1:0")?.local_addr()?.port(),
+        };
+        let dir = env::temp_dir().join(format!("nats-server-{}", Nuid::new().next_id()));
+        fs::create_dir_all(&dir)?;
+        let config_file = dir.join("nats-server.conf");
+        fs::write(&config_file, self.config(port, &dir))?;
+        let child = Command::new(&binary)
+            .arg("-c")
+            .arg(&config_file)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| {
+                let _ = fs::remove_dir_all(&dir);
+                NatsError::from((
+                    ErrorKind::InvalidClientConfig,
+                    "Unable to start the server",
+                    format!("{}: {}", binary.display(), e),
+                ))
+            })?;
+        let mut server = ServerProcess { child, port, dir };
+        server.wait_ready(
+            self.ready_timeout
+                .unwrap_or_else(|| Duration::from_millis(DEFAULT_READY_TIMEOUT_MS)),
+        )?;
+        Ok(server)
+    }
+
+    fn config(&self, port: u16, dir: &Path) -> String {
+        let mut config = format!("listen: \"127.0.0.1:{}\"\n", port);
+        if self.jetstream {
+            config.push_str(&format!(
+                "jetstream {{\n  store_dir: {:?}\n}}\n",
+                dir.join("jetstream")
+            ));
+        }
+        if let Some((ref cert_file, ref key_file)) = self.tls {
+            config.push_str(&format!(
+                "tls {{\n  cert_file: {:?}\n  key_file: {:?}\n}}\n",
+                cert_file, key_file
+            ));
+        }
+        config
+    }
+}
+
+// A running server, stopped and cleaned up when dropped
+#[derive(Debug)]
+pub struct ServerProcess {
+    child: Child,
+    port: u16,
+    dir: PathBuf,
+}
+
+impl ServerProcess {
+    // Starts a server with the default configuration
+    pub fn start() -> Result<ServerProcess, NatsError> {
+        ServerProcessBuilder::new().start()
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn url(&self) -> String {
+        format!("nats://127.0.0.1:{}", self.port)
+    }
+
+    // Temporary directory holding the configuration and JetStream data
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn client(&self) -> Result<Client, NatsError> {
+        Client::new(self.url())
+    }
+
+    // The server is ready once it sends its INFO line
+    fn wait_ready(&mut self, timeout: Duration) -> Result<(), NatsError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(status) = self.child.try_wait()? {
+                return Err(NatsError::from((
+                    ErrorKind::InvalidClientConfig,
+                    "The server exited during startup",
+                    status.to_string(),
+                )));
+            }
+            if let Ok(stream) = TcpStream::connect(("127.0.0.1", self.port)) {
+                stream.set_read_timeout(Some(Duration::from_millis(READY_POLL_INTERVAL_MS)))?;
+                let mut line = String::new();
+                if BufReader::new(stream).read_line(&mut line).is_ok() && line.starts_with("INFO ")
+                {
+                    return Ok(());
+                }
+            }
+            if Instant::now() >= deadline {
+                return Err(NatsError::from((
+                    ErrorKind::Timeout,
+                    "The server didn't become ready before the timeout",
+                )));
+            }
+            thread::sleep(Duration::from_millis(READY_POLL_INTERVAL_MS));
+        }
+    }
+}
+
+impl Drop for ServerProcess {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn find_server_binary() -> Result<PathBuf, NatsError> {
+    if let Some(binary) = env::var_os(SERVER_BINARY_ENV) {
+        return Ok(PathBuf::from(binary));
+    }
+    let name = if cfg!(windows) {
+        "nats-server.exe"
+    } else {
+        "nats-server"
+    };
+    env::var_os("PATH")
+        .and_then(|paths| {
+            env::split_paths(&paths)
+                .map(|dir| dir.join(name))
+                .find(|path| path.is_file())
+        })
+        .ok_or_else(|| {
+            NatsError::from((
+                ErrorKind::InvalidClientConfig,
+                "nats-server not found; install it or set NATS_SERVER_BIN",
+            ))
+        })
+}
+
+// Generates a self-signed certificate valid for `localhost` and 127.0.0.1
+pub fn self_signed_certificate(
+    common_name: &str,
+    days: u32,
+) -> Result<(X509, PKey<Private>), NatsError> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    let key = PKey::from_ec_key(EcKey::generate(&group)?)?;
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, common_name)?;
+    let name = name.build();
+    let mut builder = X509::builder()?;
+    builder.set_version(2)?;
+    let serial = BigNum::from_u32(rand::random::<u32>() >> 1)?.to_asn1_integer()?;
+    builder.set_serial_number(&serial)?;
+    builder.set_subject_name(&name)?;
+    builder.set_issuer_name(&name)?;
+    builder.set_not_before(&*Asn1Time::days_from_now(0)?)?;
+    builder.set_not_after(&*Asn1Time::days_from_now(days)?)?;
+    builder.set_pubkey(&key)?;
+    let san = SubjectAlternativeName::new()
+        .dns("localhost")
+        .ip("127.0.0.1")
+        .build(&builder.x509v3_context(None, None))?;
+    builder.append_extension(san)?;
+    builder.sign(&key, MessageDigest::sha256())?;
+    Ok((builder.build(), key))
+}
+
+// Accepts a single TLS connection on a loopback port, and echoes everything
+// it receives until the client closes the connection
+pub fn tls_echo_server(
+    cert: &X509,
+    key: &PKey<Private>,
+) -> Result<(u16, JoinHandle<()>), NatsError> {
+    let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+    acceptor.set_certificate(cert)?;
+    acceptor.set_private_key(key)?;
+    acceptor.check_private_key()?;
+    let acceptor = acceptor.build();
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let port = listener.local_addr()?.port();
+    let handle = thread::spawn(move || {
+        let tcp = match listener.accept() {
+            Ok((tcp, _)) => tcp,
+            Err(_) => return,
+        };
+        let mut stream = match acceptor.accept(tcp) {
+            Ok(stream) => stream,
+            Err(_) => return,
+        };
+        let mut buf = [0u8; 4096];
+        while let Ok(len) = stream.read(&mut buf) {
+            if len == 0 || stream.write_all(&buf[..len]).is_err() {
+                break;
+            }
+        }
+        let _ = stream.shutdown();
+    });
+    Ok((port, handle))
+}
+
+#[test]
+fn server_config_test() {
+    let mut builder = ServerProcessBuilder::new();
+    builder.jetstream(true).tls("cert.pem", "key.pem");
+    let config = builder.config(4333, Path::new("/tmp/nats"));
+    assert!(config.starts_with("listen: \"127.0.0.1:4333\"\n"));
+    assert!(config.contains("store_dir: \"/tmp/nats/jetstream\""));
+    assert!(config.contains("cert_file: \"cert.pem\""));
+    assert!(config.contains("key_file: \"key.pem\""));
+}
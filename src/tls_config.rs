@@ -1,6 +1,7 @@
 use openssl;
 
 use self::openssl::{
+    pkcs12::Pkcs12,
     pkey::{PKey, Private},
     ssl::{SslConnector, SslConnectorBuilder, SslMethod},
     x509::X509,
@@ -37,6 +38,38 @@ impl TlsConfigBuilder {
         Ok(self)
     }
 
+    // Uses the certificate and private key of a DER-encoded PKCS#12 bundle
+    // as the client identity, sending the other certificates of the bundle
+    // as the chain
+    pub fn add_client_identity_pkcs12(
+        &mut self,
+        der: &[u8],
+        password: &str,
+    ) -> Result<&mut Self, NatsError> {
+        let identity = Pkcs12::from_der(der)?.parse2(password)?;
+        let (cert, key) = match (identity.cert, identity.pkey) {
+            (Some(cert), Some(key)) => (cert, key),
+            _ => {
+                return Err(NatsError::from((
+                    ErrorKind::TlsError,
+                    "The PKCS#12 bundle doesn't contain a certificate and a private key",
+                )))
+            }
+        };
+        self.add_client_certificate(&cert, &key)?;
+        for cert in identity.ca.into_iter().flatten() {
+            self.0.add_extra_chain_cert(cert)?;
+        }
+        Ok(self)
+    }
+
+    // Protocols offered during the TLS handshake, in order of preference,
+    // for load balancers routing connections according to ALPN
+    pub fn set_alpn_protocols(&mut self, protocols: &[&str]) -> Result<&mut Self, NatsError> {
+        self.0.set_alpn_protos(&alpn_wire_format(protocols)?)?;
+        Ok(self)
+    }
+
     pub fn build(self) -> TlsConfig {
         TlsConfig(self.0.build())
     }
@@ -48,8 +81,114 @@ impl TlsConfig {
     }
 }
 
+// Source of the TLS configuration, invoked before every connection to a
+// server requiring TLS, so that short-lived client certificates can be
+// renewed while the client is running
+pub trait TlsConfigProvider: Send {
+    fn tls_config(&mut self) -> Result<TlsConfig, NatsError>;
+}
+
+impl<F: FnMut() -> Result<TlsConfig, NatsError> + Send> TlsConfigProvider for F {
+    fn tls_config(&mut self) -> Result<TlsConfig, NatsError> {
+        self()
+    }
+}
+
+impl fmt::Debug for dyn TlsConfigProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "TlsConfigProvider {{}}")
+    }
+}
+
 impl fmt::Debug for TlsConfig {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "TlsConfig {{}}")
     }
 }
+
+// Protocol names, each prefixed with its length
+fn alpn_wire_format(protocols: &[&str]) -> Result<Vec<u8>, NatsError> {
+    let mut wire = Vec::new();
+    for protocol in protocols {
+        if protocol.is_empty() || protocol.len() > u8::MAX as usize {
+            return Err(NatsError::from((
+                ErrorKind::InvalidClientConfig,
+                "ALPN protocol names must be between 1 and 255 bytes long",
+                (*protocol).to_owned(),
+            )));
+        }
+        wire.push(protocol.len() as u8);
+        wire.extend_from_slice(protocol.as_bytes());
+    }
+    Ok(wire)
+}
+
+#[test]
+fn tls_stream_test() {
+    use crate::stream::{SslStream, Stream};
+    use crate::testing::{self_signed_certificate, tls_echo_server};
+    use std::io::{IoSlice, Read, Write};
+    use std::net::TcpStream;
+
+    let (cert, key) = self_signed_certificate("localhost", 1).unwrap();
+    let (port, server) = tls_echo_server(&cert, &key).unwrap();
+    let mut builder = TlsConfigBuilder::new().unwrap();
+    builder.add_root_certificate(cert).unwrap();
+    let connector = builder.build().into_connector();
+    let tcp = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let conn = connector.connect("localhost", tcp).unwrap();
+    let mut writer = Stream::Ssl(SslStream::new(conn));
+    let mut reader = writer.try_clone().unwrap();
+
+    let written = writer
+        .write_vectored(&[IoSlice::new(b"PING"), IoSlice::new(b"\r\n")])
+        .unwrap();
+    writer.write_all(&b"PING\r\n"[written..]).unwrap();
+    writer.flush().unwrap();
+    let mut buf = [0u8; 6];
+    reader.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"PING\r\n");
+    assert_eq!(writer.as_tcp().unwrap().peer_addr().unwrap().port(), port);
+
+    writer.shutdown().unwrap();
+    server.join().unwrap();
+
+    // Certificate not signed by the configured root
+    let (other_cert, other_key) = self_signed_certificate("localhost", 1).unwrap();
+    let (port, server) = tls_echo_server(&other_cert, &other_key).unwrap();
+    let tcp = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    assert!(connector.connect("localhost", tcp).is_err());
+    server.join().unwrap();
+}
+
+#[test]
+fn alpn_test() {
+    assert_eq!(
+        alpn_wire_format(&["h2", "nats"]).unwrap(),
+        b"\x02h2\x04nats"
+    );
+    assert!(alpn_wire_format(&[""]).is_err());
+    let mut builder = TlsConfigBuilder::new().unwrap();
+    builder.set_alpn_protocols(&["nats"]).unwrap();
+}
+
+#[test]
+fn pkcs12_identity_test() {
+    use crate::testing::self_signed_certificate;
+
+    let (cert, key) = self_signed_certificate("client", 1).unwrap();
+    let der = Pkcs12::builder()
+        .name("client")
+        .pkey(&key)
+        .cert(&cert)
+        .build2("secret")
+        .unwrap()
+        .to_der()
+        .unwrap();
+    let mut builder = TlsConfigBuilder::new().unwrap();
+    builder.add_client_identity_pkcs12(&der, "secret").unwrap();
+    match builder.add_client_identity_pkcs12(&der, "wrong") {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::TlsError),
+        Ok(_) => panic!("PKCS#12 bundle decrypted with the wrong password"),
+    }
+}
@@ -0,0 +1,57 @@
+use std::time::{Duration, SystemTime};
+
+#[derive(Clone, Debug)]
+pub struct ServerFailure {
+    pub time: SystemTime,
+    pub error: String,
+}
+
+// State of a server of the pool, as known by the client
+#[derive(Clone, Debug)]
+pub struct ServerStatus {
+    pub host: String,
+    pub port: u16,
+    // Learned from the `connect_urls` announced by the cluster, rather than
+    // configured
+    pub discovered: bool,
+    pub active: bool,
+    pub last_failure: Option<ServerFailure>,
+    // Failed connection attempts since the client was created
+    pub failures: u64,
+    pub rtt: Option<Duration>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Topology {
+    pub servers: Vec<ServerStatus>,
+}
+
+impl Topology {
+    pub fn active(&self) -> Option<&ServerStatus> {
+        self.servers.iter().find(|server| server.active)
+    }
+}
+
+// Parses `host:port` and `[ipv6]:port` entries of `connect_urls`
+pub(crate) fn parse_server_address(address: &str) -> Option<(String, u16)> {
+    let (host, port) = address.rsplit_once(':')?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return None;
+    }
+    Some((host.to_owned(), port.parse().ok()?))
+}
+
+#[test]
+fn parse_server_address_test() {
+    assert_eq!(
+        parse_server_address("10.0.0.1:4222"),
+        Some(("10.0.0.1".to_owned(), 4222))
+    );
+    assert_eq!(
+        parse_server_address("[::1]:4223"),
+        Some(("::1".to_owned(), 4223))
+    );
+    assert_eq!(parse_server_address("10.0.0.1"), None);
+    assert_eq!(parse_server_address(":4222"), None);
+}
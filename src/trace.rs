@@ -0,0 +1,107 @@
+use bytes::Bytes;
+use std::{
+    collections::VecDeque,
+    fmt::Write,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// An outbound protocol command, as recorded by the command trace
+#[derive(Clone, Debug)]
+pub struct TraceEntry {
+    pub time: SystemTime,
+    // Control line, without the trailing CRLF
+    pub command: String,
+    pub payload_len: usize,
+    // Only kept if payloads are traced
+    pub payload: Option<Bytes>,
+}
+
+// Bounded ring buffer of the last outbound commands, shared by the successive
+// connections of a client
+#[derive(Clone, Debug)]
+pub(crate) struct CommandTrace(Arc<Mutex<TraceState>>);
+
+#[derive(Debug)]
+struct TraceState {
+    capacity: usize,
+    with_payloads: bool,
+    entries: VecDeque<TraceEntry>,
+}
+
+impl CommandTrace {
+    pub fn new(capacity: usize, with_payloads: bool) -> CommandTrace {
+        CommandTrace(Arc::new(Mutex::new(TraceState {
+            capacity,
+            with_payloads,
+            entries: VecDeque::with_capacity(capacity),
+        })))
+    }
+
+    pub fn record(&self, command: &[u8], payload: &[u8]) {
+        let end = command
+            .iter()
+            .position(|&c| c == b'\r')
+            .unwrap_or(command.len());
+        let mut state = self.0.lock().unwrap();
+        if state.capacity == 0 {
+            return;
+        }
+        if state.entries.len() >= state.capacity {
+            state.entries.pop_front();
+        }
+        let payload_len = payload.len();
+        let payload = if state.with_payloads {
+            Some(Bytes::copy_from_slice(payload))
+        } else {
+            None
+        };
+        state.entries.push_back(TraceEntry {
+            time: SystemTime::now(),
+            command: String::from_utf8_lossy(&command[..end]).into_owned(),
+            payload_len,
+            payload,
+        });
+    }
+
+    pub fn entries(&self) -> Vec<TraceEntry> {
+        self.0.lock().unwrap().entries.iter().cloned().collect()
+    }
+
+    // One line per command: timestamp in milliseconds, command, and the
+    // payload if it was kept
+    pub fn dump(&self) -> String {
+        let mut out = String::new();
+        for entry in self.0.lock().unwrap().entries.iter() {
+            let ts = entry
+                .time
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |ts| ts.as_millis());
+            let _ = write!(out, "{} {}", ts, entry.command);
+            if let Some(ref payload) = entry.payload {
+                let _ = write!(out, " {:?}", String::from_utf8_lossy(payload));
+            }
+            out.push('\n');
+        }
+        out
+    }
+}
+
+#[test]
+fn command_trace_test() {
+    let trace = CommandTrace::new(2, true);
+    trace.record(b"SUB a 1\r\n", b"");
+    trace.record(b"PUB a 5\r\n", b"hello");
+    trace.record(b"PUB b 2\r\n", b"hi");
+    let entries = trace.entries();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].command, "PUB a 5");
+    assert_eq!(entries[0].payload_len, 5);
+    assert_eq!(entries[1].payload.as_deref(), Some(&b"hi"[..]));
+    assert!(trace.dump().ends_with(" PUB b 2 \"hi\"\n"));
+
+    let trace = CommandTrace::new(4, false);
+    trace.record(b"PUB a 5\r\n", b"hello");
+    assert_eq!(trace.entries()[0].payload, None);
+    assert_eq!(trace.entries()[0].payload_len, 5);
+}